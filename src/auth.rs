@@ -0,0 +1,102 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use crate::config::AuthConfig;
+
+const MAX_NAME_LEN: usize = 24;
+
+/// Who a connection belongs to, taken from a verified token.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub player_id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    name: String,
+    exp: u64,
+}
+
+/// Issues and verifies `base64url(claims).base64url(hmac-sha256)` tokens signed with `auth.secret`,
+/// so any service that shares the secret can mint identities the server will accept.
+#[derive(Debug, Clone)]
+pub struct Auth {
+    config: AuthConfig,
+}
+
+impl Auth {
+    pub fn new(config: AuthConfig) -> Auth {
+        Auth { config }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.config.secret.is_empty()
+    }
+
+    pub fn required(&self) -> bool {
+        self.config.required
+    }
+
+    /// Issues a token for a fresh player id.
+    pub fn issue_guest(&self, name: &str) -> Result<(String, Identity), String> {
+        let identity = Identity {
+            player_id: format!("{:032x}", rand::thread_rng().gen::<u128>()),
+            name: validate_name(name)?,
+        };
+        Ok((self.issue(&identity), identity))
+    }
+
+    pub fn issue(&self, identity: &Identity) -> String {
+        let claims = Claims {
+            sub: identity.player_id.clone(),
+            name: identity.name.clone(),
+            exp: now() + self.config.token_ttl_secs,
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(payload.as_bytes()).finalize().into_bytes());
+        format!("{payload}.{signature}")
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Identity, String> {
+        let (payload, signature) = token.split_once('.').ok_or("malformed token")?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| "malformed token")?;
+        self.mac(payload.as_bytes()).verify_slice(&signature).map_err(|_| "invalid token signature")?;
+        let claims = URL_SAFE_NO_PAD.decode(payload).ok()
+            .and_then(|json| serde_json::from_slice::<Claims>(&json).ok())
+            .ok_or("malformed token")?;
+        if claims.exp < now() {
+            return Err("token expired".to_string());
+        }
+        Ok(Identity { player_id: claims.sub, name: claims.name })
+    }
+
+    fn mac(&self, data: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.config.secret.as_bytes()).unwrap();
+        mac.update(data);
+        mac
+    }
+}
+
+/// Trims a requested display name and checks it is printable and not too long.
+pub fn validate_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("name must not be empty".to_string());
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(format!("name must be at most {MAX_NAME_LEN} characters"));
+    }
+    if name.chars().any(char::is_control) {
+        return Err("name must not contain control characters".to_string());
+    }
+    Ok(name.to_string())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
@@ -0,0 +1,226 @@
+use std::collections::{HashSet, VecDeque};
+use std::{fmt, fs};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use crate::config::ChatConfig;
+
+/// Channels belonging to a game room are named `room:<name>`; global channels come from config.
+pub const ROOM_CHANNEL_PREFIX: &str = "room:";
+
+pub fn room_channel(room: &str) -> String {
+    format!("{ROOM_CHANNEL_PREFIX}{room}")
+}
+
+/// A message delivered to a channel, stamped by the server with its sender and time.
+#[derive(Serialize, Debug, Clone)]
+pub struct ChatLine {
+    pub channel: String,
+    /// Sender's display name.
+    pub from: String,
+    /// Sender's session id, the target for moderation commands.
+    pub from_id: usize,
+    pub text: String,
+    /// When the server accepted the message, in Unix milliseconds.
+    pub ts: u64,
+}
+
+/// A chat channel: who receives its messages and the most recent ones, oldest first.
+#[derive(Debug, Default)]
+pub struct Channel {
+    pub members: HashSet<usize>,
+    history: VecDeque<ChatLine>,
+}
+
+impl Channel {
+    /// Keeps `line` in the history, dropping the oldest entries beyond `limit`.
+    pub fn record(&mut self, line: ChatLine, limit: usize) {
+        self.history.push_back(line);
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn history(&self) -> Vec<&ChatLine> {
+        self.history.iter().collect()
+    }
+}
+
+/// Milliseconds since the Unix epoch, the timestamp carried by chat messages.
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Checks chat text before it is delivered, returning the text to send (possibly altered)
+/// or the reason it was refused.
+pub trait ChatFilter: fmt::Debug + Send {
+    fn apply(&self, text: &str) -> Result<String, String>;
+}
+
+/// Masks blocked words with `*`, matching whole words case-insensitively.
+#[derive(Debug, Default)]
+pub struct WordFilter {
+    words: HashSet<String>,
+}
+
+impl WordFilter {
+    /// Reads one word per line from `path`, skipping blank lines and `#` comments.
+    /// An empty path gives a filter that lets everything through.
+    pub fn load(path: &str) -> Result<WordFilter, String> {
+        if path.is_empty() {
+            return Ok(WordFilter::default());
+        }
+        let text = fs::read_to_string(path).map_err(|err| format!("unable to read chat filter {path}: {err}"))?;
+        let words = text.lines()
+            .map(|line| line.trim().to_lowercase())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+        Ok(WordFilter { words })
+    }
+
+    fn push_word(&self, out: &mut String, word: &str) {
+        if self.words.contains(&word.to_lowercase()) {
+            out.extend(word.chars().map(|_| '*'));
+        } else {
+            out.push_str(word);
+        }
+    }
+}
+
+impl ChatFilter for WordFilter {
+    fn apply(&self, text: &str) -> Result<String, String> {
+        if self.words.is_empty() {
+            return Ok(text.to_string());
+        }
+        let mut out = String::with_capacity(text.len());
+        let mut start = None;
+        for (idx, c) in text.char_indices() {
+            if c.is_alphanumeric() {
+                start.get_or_insert(idx);
+            } else {
+                if let Some(start) = start.take() {
+                    self.push_word(&mut out, &text[start..idx]);
+                }
+                out.push(c);
+            }
+        }
+        if let Some(start) = start {
+            self.push_word(&mut out, &text[start..]);
+        }
+        Ok(out)
+    }
+}
+
+/// Sliding window over the times a session recently sent a message.
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    sent: VecDeque<Instant>,
+}
+
+impl RateLimit {
+    /// Records a message and returns true if fewer than `limit` were sent within `window`.
+    pub fn allow(&mut self, limit: usize, window: Duration) -> bool {
+        let now = Instant::now();
+        while self.sent.front().is_some_and(|sent| now.duration_since(*sent) >= window) {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= limit {
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+
+    /// True once nothing was sent within `window`, so the limit can be forgotten.
+    pub fn is_idle(&self, window: Duration) -> bool {
+        self.sent.back().is_none_or(|sent| sent.elapsed() >= window)
+    }
+}
+
+/// Who mutes and rate limits apply to: the player behind an authenticated
+/// session, so reconnecting does not reset them, otherwise the session itself.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Speaker {
+    PLAYER(String),
+    SESSION(usize),
+}
+
+/// Moderation commands typed into chat, e.g. `/mute 4 60`.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Mute a session, for the given seconds or the configured default.
+    MUTE(usize, Option<u64>),
+    UNMUTE(usize),
+    KICK(usize),
+}
+
+impl Command {
+    /// Parses a message starting with `/`; targets are session ids.
+    pub fn parse(text: &str) -> Result<Command, String> {
+        let mut args = text.trim_start_matches('/').split_whitespace();
+        let name = args.next().unwrap_or_default();
+        if !matches!(name, "mute" | "unmute" | "kick") {
+            return Err(format!("unknown command /{name}"));
+        }
+        let target = args.next()
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| format!("usage: /{name} <session id>"))?;
+        let command = match name {
+            "mute" => {
+                let secs = match args.next() {
+                    Some(secs) => Some(secs.parse().map_err(|_| "usage: /mute <session id> [seconds]")?),
+                    None => None,
+                };
+                Command::MUTE(target, secs)
+            },
+            "unmute" => Command::UNMUTE(target),
+            _ => Command::KICK(target),
+        };
+        Ok(command)
+    }
+
+    pub fn target(&self) -> usize {
+        match self {
+            Command::MUTE(target, _) | Command::UNMUTE(target) | Command::KICK(target) => *target,
+        }
+    }
+}
+
+/// When a `/mute` for `secs` (or the configured default) issued at `now` ends.
+/// Durations beyond `chat.max_mute_secs` are refused rather than clamped, so the moderator knows.
+pub fn mute_until(secs: Option<u64>, config: &ChatConfig, now: Instant) -> Result<Instant, String> {
+    let duration = secs.map(Duration::from_secs).unwrap_or(config.mute());
+    if duration > config.max_mute() {
+        return Err(format!("mutes can last at most {} seconds", config.max_mute_secs));
+    }
+    now.checked_add(duration).ok_or_else(|| "mute is too long".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_moderation_commands() {
+        assert_eq!(Command::parse("/mute 4"), Ok(Command::MUTE(4, None)));
+        assert_eq!(Command::parse("/mute 4 60"), Ok(Command::MUTE(4, Some(60))));
+        assert_eq!(Command::parse("/unmute 4"), Ok(Command::UNMUTE(4)));
+        assert_eq!(Command::parse("/kick 7"), Ok(Command::KICK(7)));
+        assert_eq!(Command::parse("/mute 4 18446744073709551615"), Ok(Command::MUTE(4, Some(u64::MAX))));
+        assert!(Command::parse("/ban 4").is_err());
+        assert!(Command::parse("/kick").is_err());
+        assert!(Command::parse("/kick bob").is_err());
+        assert!(Command::parse("/mute 4 forever").is_err());
+        assert!(Command::parse("/mute 4 18446744073709551616").is_err());
+    }
+
+    #[test]
+    fn mute_uses_default_and_refuses_oversized_durations() {
+        let config = ChatConfig::default();
+        let now = Instant::now();
+        assert_eq!(mute_until(None, &config, now), Ok(now + config.mute()));
+        assert_eq!(mute_until(Some(60), &config, now), Ok(now + Duration::from_secs(60)));
+        assert_eq!(mute_until(Some(config.max_mute_secs), &config, now), Ok(now + config.max_mute()));
+        assert!(mute_until(Some(config.max_mute_secs + 1), &config, now).is_err());
+        assert!(mute_until(Some(u64::MAX), &config, now).is_err());
+    }
+}
@@ -0,0 +1,500 @@
+use std::{env, fs, str::FromStr, sync::Arc, time::Duration};
+use serde::Deserialize;
+use crate::chat::ROOM_CHANNEL_PREFIX;
+use crate::game::common::Board;
+use crate::game::level::Level;
+
+const CONFIG_PATH_VAR: &str = "SPACEWS_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "spacews.toml";
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub session: SessionConfig,
+    pub game: GameConfig,
+    pub leaderboard: LeaderboardConfig,
+    pub admin: AdminConfig,
+    pub auth: AuthConfig,
+    pub matchmaking: MatchmakingConfig,
+    pub rooms: RoomsConfig,
+    pub chat: ChatConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub port: u16,
+    pub workers: usize,
+    pub cors_origins: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    pub heartbeat_secs: u64,
+    pub client_timeout_secs: u64,
+    /// How long a dropped player's slot is held for a RESUME before it is given up.
+    pub resume_grace_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameConfig {
+    pub tick_ms: u64,
+    /// Seconds between everyone readying up in the lobby and the first wave.
+    pub countdown_secs: u64,
+    pub board: Board,
+    pub player: PlayerConfig,
+    pub invaders: InvaderConfig,
+    pub waves: WaveConfig,
+    pub levels_dir: String,
+    /// Seed every room's game uses unless the JOIN gives one, for reproducing a reported game.
+    /// Rooms pick a random seed when it is unset.
+    pub seed: Option<u64>,
+    /// Levels loaded from `levels_dir` at startup.
+    #[serde(skip)]
+    pub levels: Arc<Vec<Level>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token required by the `/admin` endpoints; they are disabled while it is empty.
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomsConfig {
+    /// Seconds a room may sit with nobody connected, or finished, before it is closed.
+    pub idle_timeout_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatConfig {
+    /// Global channels sessions can SUBSCRIBE to; every game room also gets its own.
+    pub channels: Vec<String>,
+    /// Messages each channel keeps and replays to new members.
+    pub history: usize,
+    pub max_message_len: usize,
+    /// Messages a session may send within `rate_limit_secs`.
+    pub rate_limit_messages: usize,
+    pub rate_limit_secs: u64,
+    /// How long `/mute` silences a session when no duration is given.
+    pub mute_secs: u64,
+    /// Longest mute `/mute` accepts.
+    pub max_mute_secs: u64,
+    /// Word list for the chat filter, one word per line; empty disables it.
+    pub filter_path: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchmakingConfig {
+    /// Seconds a session waits for opponents before it is given a solo game.
+    pub timeout_secs: u64,
+    /// Largest rating gap accepted straight away.
+    pub rating_window: f64,
+    /// How much the accepted gap widens for every second spent waiting.
+    pub rating_window_growth: f64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// HMAC key player tokens are signed with; tokens are ignored while it is empty.
+    pub secret: String,
+    pub token_ttl_secs: u64,
+    /// Refuse WebSocket connections that do not present a valid token.
+    pub required: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaderboardConfig {
+    /// SQLite file finished games are saved to; created on first start.
+    pub path: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WaveConfig {
+    pub count: usize,
+    pub intermission_ms: u64,
+    pub speedup_percent: u64,
+    pub fire_from_wave: usize,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlayerConfig {
+    pub lives: u8,
+    pub invulnerable_ms: u64,
+    /// Queued inputs applied to a player at the start of each tick.
+    pub inputs_per_tick: usize,
+    /// Inputs a player may have queued; more are refused until the queue drains.
+    pub input_buffer: usize,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct InvaderConfig {
+    pub move_ms: u64,
+    pub min_move_ms: u64,
+    pub speedup_ms: u64,
+    pub fire_min_ms: u64,
+    pub fire_max_ms: u64,
+    pub bomb_fall_ms: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "127.0.0.1".to_string(),
+            port: 8089,
+            workers: 2,
+            cors_origins: vec![
+                "http://localhost:3000".to_string(),
+                "http://localhost:8080".to_string(),
+            ],
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_secs: 5,
+            client_timeout_secs: 10,
+            resume_grace_secs: 30,
+        }
+    }
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            tick_ms: 100,
+            countdown_secs: 3,
+            board: Board::default(),
+            player: PlayerConfig::default(),
+            invaders: InvaderConfig::default(),
+            waves: WaveConfig::default(),
+            levels_dir: "levels".to_string(),
+            seed: None,
+            levels: Arc::new(Vec::new()),
+        }
+    }
+}
+
+impl Default for RoomsConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: 120,
+        }
+    }
+}
+
+impl RoomsConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            channels: vec!["main".to_string()],
+            history: 50,
+            max_message_len: 500,
+            rate_limit_messages: 5,
+            rate_limit_secs: 10,
+            mute_secs: 300,
+            max_mute_secs: 24 * 60 * 60,
+            filter_path: String::new(),
+        }
+    }
+}
+
+impl ChatConfig {
+    pub fn rate_window(&self) -> Duration {
+        Duration::from_secs(self.rate_limit_secs)
+    }
+
+    pub fn mute(&self) -> Duration {
+        Duration::from_secs(self.mute_secs)
+    }
+
+    pub fn max_mute(&self) -> Duration {
+        Duration::from_secs(self.max_mute_secs)
+    }
+}
+
+impl Default for MatchmakingConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 30,
+            rating_window: 5.0,
+            rating_window_growth: 1.0,
+        }
+    }
+}
+
+impl MatchmakingConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            secret: String::new(),
+            token_ttl_secs: 30 * 24 * 60 * 60,
+            required: false,
+        }
+    }
+}
+
+impl Default for LeaderboardConfig {
+    fn default() -> Self {
+        Self {
+            path: "spacews.db".to_string(),
+        }
+    }
+}
+
+impl Default for InvaderConfig {
+    fn default() -> Self {
+        Self {
+            move_ms: 2500,
+            min_move_ms: 250,
+            speedup_ms: 250,
+            fire_min_ms: 1500,
+            fire_max_ms: 4000,
+            bomb_fall_ms: 200,
+        }
+    }
+}
+
+impl Default for WaveConfig {
+    fn default() -> Self {
+        Self {
+            count: 5,
+            intermission_ms: 3000,
+            speedup_percent: 15,
+            fire_from_wave: 2,
+        }
+    }
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        Self {
+            lives: 3,
+            invulnerable_ms: 2000,
+            inputs_per_tick: 2,
+            input_buffer: 8,
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn heartbeat(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_secs)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_timeout_secs)
+    }
+
+    pub fn resume_grace(&self) -> Duration {
+        Duration::from_secs(self.resume_grace_secs)
+    }
+}
+
+impl GameConfig {
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.tick_ms)
+    }
+
+    /// Invader timing for `level` played as the given wave: every wave after the first moves and fires
+    /// `waves.speedup_percent` faster than the one before, never dropping below `min_move_ms`.
+    pub fn timing_for_wave(&self, level: &Level, wave: usize) -> InvaderConfig {
+        let mut timing = level.timing(&self.invaders);
+        let scale = |ms: u64| {
+            (0..wave.saturating_sub(1)).fold(ms, |ms, _| ms * (100 - self.waves.speedup_percent) / 100).max(1)
+        };
+        timing.move_ms = scale(timing.move_ms).max(timing.min_move_ms);
+        timing.fire_min_ms = scale(timing.fire_min_ms);
+        timing.fire_max_ms = scale(timing.fire_max_ms);
+        timing
+    }
+}
+
+impl Config {
+    /// Reads the TOML file named by `SPACEWS_CONFIG` (default `spacews.toml`, skipped if absent),
+    /// applies `SPACEWS_*` environment overrides and validates the result.
+    pub fn load() -> Result<Config, String> {
+        let path = env::var(CONFIG_PATH_VAR).ok();
+        let mut config = match fs::read_to_string(path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH)) {
+            Ok(contents) => toml::from_str::<Config>(&contents)
+                .map_err(|err| format!("invalid config file: {err}"))?,
+            Err(err) if path.is_some() => {
+                return Err(format!("unable to read config file {}: {err}", path.unwrap()))
+            },
+            Err(_) => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        config.game.levels = Arc::new(Level::load_dir(&config.game.levels_dir, &config.game.invaders)?);
+        for level in config.game.levels.iter() {
+            level.fits(config.game.board)?;
+        }
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), String> {
+        env_override("SPACEWS_BIND_ADDRESS", &mut self.server.bind_address)?;
+        env_override("SPACEWS_PORT", &mut self.server.port)?;
+        env_override("SPACEWS_WORKERS", &mut self.server.workers)?;
+        if let Ok(origins) = env::var("SPACEWS_CORS_ORIGINS") {
+            self.server.cors_origins = split_list(&origins);
+        }
+        env_override("SPACEWS_HEARTBEAT_SECS", &mut self.session.heartbeat_secs)?;
+        env_override("SPACEWS_CLIENT_TIMEOUT_SECS", &mut self.session.client_timeout_secs)?;
+        env_override("SPACEWS_RESUME_GRACE_SECS", &mut self.session.resume_grace_secs)?;
+        env_override("SPACEWS_TICK_MS", &mut self.game.tick_ms)?;
+        env_override("SPACEWS_COUNTDOWN_SECS", &mut self.game.countdown_secs)?;
+        if let Ok(seed) = env::var("SPACEWS_GAME_SEED") {
+            self.game.seed = Some(seed.parse().map_err(|err| format!("invalid SPACEWS_GAME_SEED {seed:?}: {err}"))?);
+        }
+        env_override("SPACEWS_BOARD_COLS", &mut self.game.board.cols)?;
+        env_override("SPACEWS_BOARD_ROWS", &mut self.game.board.rows)?;
+        env_override("SPACEWS_INVADER_MOVE_MS", &mut self.game.invaders.move_ms)?;
+        env_override("SPACEWS_INVADER_MIN_MOVE_MS", &mut self.game.invaders.min_move_ms)?;
+        env_override("SPACEWS_INVADER_SPEEDUP_MS", &mut self.game.invaders.speedup_ms)?;
+        env_override("SPACEWS_INVADER_FIRE_MIN_MS", &mut self.game.invaders.fire_min_ms)?;
+        env_override("SPACEWS_INVADER_FIRE_MAX_MS", &mut self.game.invaders.fire_max_ms)?;
+        env_override("SPACEWS_INVADER_BOMB_FALL_MS", &mut self.game.invaders.bomb_fall_ms)?;
+        env_override("SPACEWS_LEVELS_DIR", &mut self.game.levels_dir)?;
+        env_override("SPACEWS_WAVE_COUNT", &mut self.game.waves.count)?;
+        env_override("SPACEWS_WAVE_INTERMISSION_MS", &mut self.game.waves.intermission_ms)?;
+        env_override("SPACEWS_WAVE_SPEEDUP_PERCENT", &mut self.game.waves.speedup_percent)?;
+        env_override("SPACEWS_WAVE_FIRE_FROM", &mut self.game.waves.fire_from_wave)?;
+        env_override("SPACEWS_PLAYER_LIVES", &mut self.game.player.lives)?;
+        env_override("SPACEWS_PLAYER_INVULNERABLE_MS", &mut self.game.player.invulnerable_ms)?;
+        env_override("SPACEWS_PLAYER_INPUTS_PER_TICK", &mut self.game.player.inputs_per_tick)?;
+        env_override("SPACEWS_PLAYER_INPUT_BUFFER", &mut self.game.player.input_buffer)?;
+        env_override("SPACEWS_LEADERBOARD_PATH", &mut self.leaderboard.path)?;
+        env_override("SPACEWS_ADMIN_TOKEN", &mut self.admin.token)?;
+        env_override("SPACEWS_AUTH_SECRET", &mut self.auth.secret)?;
+        env_override("SPACEWS_AUTH_TOKEN_TTL_SECS", &mut self.auth.token_ttl_secs)?;
+        env_override("SPACEWS_AUTH_REQUIRED", &mut self.auth.required)?;
+        env_override("SPACEWS_ROOM_IDLE_TIMEOUT_SECS", &mut self.rooms.idle_timeout_secs)?;
+        if let Ok(channels) = env::var("SPACEWS_CHAT_CHANNELS") {
+            self.chat.channels = split_list(&channels);
+        }
+        env_override("SPACEWS_CHAT_HISTORY", &mut self.chat.history)?;
+        env_override("SPACEWS_CHAT_MAX_MESSAGE_LEN", &mut self.chat.max_message_len)?;
+        env_override("SPACEWS_CHAT_RATE_LIMIT_MESSAGES", &mut self.chat.rate_limit_messages)?;
+        env_override("SPACEWS_CHAT_RATE_LIMIT_SECS", &mut self.chat.rate_limit_secs)?;
+        env_override("SPACEWS_CHAT_MUTE_SECS", &mut self.chat.mute_secs)?;
+        env_override("SPACEWS_CHAT_MAX_MUTE_SECS", &mut self.chat.max_mute_secs)?;
+        env_override("SPACEWS_CHAT_FILTER_PATH", &mut self.chat.filter_path)?;
+        env_override("SPACEWS_MATCHMAKING_TIMEOUT_SECS", &mut self.matchmaking.timeout_secs)?;
+        env_override("SPACEWS_MATCHMAKING_RATING_WINDOW", &mut self.matchmaking.rating_window)?;
+        env_override("SPACEWS_MATCHMAKING_RATING_WINDOW_GROWTH", &mut self.matchmaking.rating_window_growth)?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.server.bind_address.is_empty() {
+            return Err("server.bind_address must not be empty".to_string());
+        }
+        if self.server.workers == 0 {
+            return Err("server.workers must be at least 1".to_string());
+        }
+        if self.session.heartbeat_secs == 0 {
+            return Err("session.heartbeat_secs must be at least 1".to_string());
+        }
+        if self.session.client_timeout_secs <= self.session.heartbeat_secs {
+            return Err("session.client_timeout_secs must be greater than session.heartbeat_secs".to_string());
+        }
+        if self.game.tick_ms == 0 {
+            return Err("game.tick_ms must be at least 1".to_string());
+        }
+        self.game.board.validate()?;
+        let invaders = &self.game.invaders;
+        if invaders.min_move_ms == 0 || invaders.min_move_ms > invaders.move_ms {
+            return Err("game.invaders.min_move_ms must be between 1 and game.invaders.move_ms".to_string());
+        }
+        if invaders.fire_min_ms == 0 || invaders.fire_min_ms > invaders.fire_max_ms {
+            return Err("game.invaders.fire_min_ms must be between 1 and game.invaders.fire_max_ms".to_string());
+        }
+        if invaders.bomb_fall_ms == 0 {
+            return Err("game.invaders.bomb_fall_ms must be at least 1".to_string());
+        }
+        if self.game.waves.count == 0 {
+            return Err("game.waves.count must be at least 1".to_string());
+        }
+        if self.game.waves.speedup_percent >= 100 {
+            return Err("game.waves.speedup_percent must be below 100".to_string());
+        }
+        if self.game.player.lives == 0 {
+            return Err("game.player.lives must be at least 1".to_string());
+        }
+        if self.game.player.inputs_per_tick == 0 || self.game.player.input_buffer < self.game.player.inputs_per_tick {
+            return Err("game.player.inputs_per_tick must be at least 1 and no more than input_buffer".to_string());
+        }
+        if self.auth.required && self.auth.secret.is_empty() {
+            return Err("auth.required needs auth.secret to be set".to_string());
+        }
+        if self.auth.token_ttl_secs == 0 {
+            return Err("auth.token_ttl_secs must be at least 1".to_string());
+        }
+        if self.rooms.idle_timeout_secs <= self.session.resume_grace_secs {
+            return Err("rooms.idle_timeout_secs must be greater than session.resume_grace_secs".to_string());
+        }
+        if self.matchmaking.rating_window < 0.0 || self.matchmaking.rating_window_growth < 0.0 {
+            return Err("matchmaking.rating_window and rating_window_growth must not be negative".to_string());
+        }
+        if self.leaderboard.path.is_empty() {
+            return Err("leaderboard.path must not be empty".to_string());
+        }
+        if self.chat.channels.iter().any(|name| name.is_empty() || name.starts_with(ROOM_CHANNEL_PREFIX)) {
+            return Err(format!("chat.channels must be non-empty names not starting with {ROOM_CHANNEL_PREFIX:?}"));
+        }
+        if self.chat.max_message_len == 0 {
+            return Err("chat.max_message_len must be at least 1".to_string());
+        }
+        if self.chat.rate_limit_messages == 0 || self.chat.rate_limit_secs == 0 {
+            return Err("chat.rate_limit_messages and rate_limit_secs must be at least 1".to_string());
+        }
+        if self.chat.mute_secs > self.chat.max_mute_secs {
+            return Err("chat.mute_secs must not be greater than chat.max_mute_secs".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Splits a comma separated environment value, dropping empty entries.
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn env_override<T: FromStr>(key: &str, target: &mut T) -> Result<(), String>
+where
+    T::Err: std::fmt::Display,
+{
+    if let Ok(value) = env::var(key) {
+        *target = value.parse().map_err(|err| format!("invalid {key} {value:?}: {err}"))?;
+    }
+    Ok(())
+}
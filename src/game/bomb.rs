@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use rusty_time::timer::Timer;
+
+use crate::game::frame::{Drawable, Frame};
+use crate::game::snapshot::{Entity, Snapshotable};
+
+pub struct Bomb {
+    pub id: usize,
+    pub x: usize,
+    pub y: usize,
+    max_y: usize,
+    timer: Timer,
+    hit: bool,
+}
+
+impl Bomb {
+    pub fn new(id: usize, x: usize, y: usize, max_y: usize, fall_ms: u64) -> Self {
+        Self {
+            id,
+            x,
+            y,
+            max_y,
+            timer: Timer::from_millis(fall_ms),
+            hit: false,
+        }
+    }
+
+    pub fn update(&mut self, delta: Duration){
+        self.timer.update(delta);
+        if self.timer.ready {
+            if self.y < self.max_y {
+                self.y += 1;
+            } else {
+                self.hit = true;
+            }
+            self.timer.reset();
+        }
+    }
+
+    pub fn detonate(&mut self){
+        self.hit = true;
+    }
+
+    pub fn dead(&self) -> bool {
+        self.hit
+    }
+}
+
+impl Drawable for Bomb {
+    fn draw(&self, frame: &mut Frame) {
+        frame[self.y][self.x] = "!";
+    }
+}
+
+impl Snapshotable for Bomb {
+    fn snapshot(&self, entities: &mut Vec<Entity>) {
+        entities.push(Entity::BOMB { id: self.id, x: self.x, y: self.y });
+    }
+}
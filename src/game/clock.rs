@@ -0,0 +1,26 @@
+use std::{fmt, time::{Duration, Instant}};
+
+/// Time source that drives a room's simulation. The game only reads it to work out how many
+/// fixed-length ticks are due, so replays and tests can drive a game with a clock of their own.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Time elapsed since some fixed starting point; only differences between readings matter.
+    fn now(&self) -> Duration;
+}
+
+/// Wall-clock time since the clock was created.
+#[derive(Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
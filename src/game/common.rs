@@ -1,2 +1,35 @@
-pub const NUM_COLS: usize = 15;
-pub const NUM_ROWS: usize = 15;
\ No newline at end of file
+use serde::Deserialize;
+
+pub const DEFAULT_COLS: usize = 15;
+pub const DEFAULT_ROWS: usize = 15;
+pub const MIN_BOARD_SIZE: usize = 5;
+pub const MAX_BOARD_SIZE: usize = 255;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Board {
+    pub cols: usize,
+    pub rows: usize,
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Self {
+            cols: DEFAULT_COLS,
+            rows: DEFAULT_ROWS,
+        }
+    }
+}
+
+impl Board {
+    pub fn validate(&self) -> Result<(), String> {
+        let range = MIN_BOARD_SIZE..=MAX_BOARD_SIZE;
+        if !range.contains(&self.cols) || !range.contains(&self.rows) {
+            return Err(format!(
+                "board must be between {MIN_BOARD_SIZE} and {MAX_BOARD_SIZE} cells on each side, got {}x{}",
+                self.cols, self.rows
+            ));
+        }
+        Ok(())
+    }
+}
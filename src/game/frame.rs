@@ -1,19 +1,11 @@
-use crate::game::common::{NUM_COLS, NUM_ROWS};
+use crate::game::common::Board;
 
 pub type Frame = Vec<Vec<&'static str>>;
 
-pub fn new_frame() -> Frame{
-    let mut cols = Vec::with_capacity(NUM_ROWS);
-    for _ in 0..NUM_ROWS {
-        let mut col = Vec::with_capacity(NUM_COLS);
-        for _ in 0..NUM_COLS{
-            col.push(" ");
-        }
-        cols.push(col);
-    }
-    cols
+pub fn new_frame(board: Board) -> Frame{
+    vec![vec![" "; board.cols]; board.rows]
 }
 
 pub trait Drawable {
     fn draw(&self, frame: &mut Frame);
-}
\ No newline at end of file
+}
@@ -1,43 +1,77 @@
 use std::{cmp::max, time::Duration};
 
+use rand::{rngs::StdRng, Rng};
 use rusty_time::timer::Timer;
 
-use crate::game::common::{NUM_COLS , NUM_ROWS};
+use crate::config::InvaderConfig;
+use crate::game::bomb::Bomb;
+use crate::game::common::Board;
 use crate::game::frame::{Drawable, Frame};
+use crate::game::level::InvaderType;
+use crate::game::snapshot::{Entity, Snapshotable};
 
 pub struct Invader {
+    id: usize,
     x: usize,
     y: usize,
+    class: String,
+    points: usize,
+    hp: u8,
+}
+
+impl Invader {
+    pub fn new(id: usize, x: usize, y: usize, kind: &InvaderType) -> Self {
+        Self {
+            id,
+            x,
+            y,
+            class: kind.name.clone(),
+            points: kind.points,
+            hp: kind.hp,
+        }
+    }
+
+    pub fn entity(&self, phase: u8) -> Entity {
+        Entity::INVADER { id: self.id, class: self.class.clone(), x: self.x, y: self.y, hp: self.hp, phase }
+    }
+}
+
+/// Outcome of a shot landing on an invader; `points` is only earned on a kill.
+pub struct Hit {
+    pub killed: bool,
+    pub points: usize,
 }
 
 pub struct Invaders {
     pub army: Vec<Invader>,
+    pub bombs: Vec<Bomb>,
+    board: Board,
+    timing: InvaderConfig,
     move_timer: Timer,
+    fire_timer: Timer,
+    firing: bool,
+    next_bomb_id: usize,
     direction: i32,
     stop: bool,
+    /// Seeded from the game session so firing is the same every time a seed is replayed.
+    rng: StdRng,
 }
 
 impl Invaders {
-    pub fn new() -> Self {
-        let mut army = Vec::new();
-        for x in 0..NUM_COLS {
-            for y in 0..NUM_ROWS {
-                if(y > 1)
-                    && (y < NUM_ROWS / 2)
-                    && (x >  0)
-                    && (x < NUM_COLS)
-                    && (x % 2 == 0)
-                    && (y % 2 == 0){
-                        army.push(Invader{x, y});
-                    }
-            }
-        }
+    pub fn new(board: Board, timing: InvaderConfig, army: Vec<Invader>, firing: bool, mut rng: StdRng) -> Self {
 
         Self { 
             army,
-            move_timer: Timer::from_millis(2500), 
+            bombs: Vec::new(),
+            board,
+            move_timer: Timer::from_millis(timing.move_ms),
+            fire_timer: Timer::from_millis(random_fire_ms(&timing, &mut rng)),
+            firing,
+            next_bomb_id: 0,
+            timing,
             direction: 1, 
-            stop: false
+            stop: false,
+            rng,
         }
     }
 
@@ -46,6 +80,7 @@ impl Invaders {
         if self.stop {
             return true
         }
+        self.update_bombs(delta);
         self.move_timer.update(delta);
         if self.move_timer.ready {
             self.move_timer.reset();
@@ -63,13 +98,16 @@ impl Invaders {
                     .map(|invader| invader.x)
                     .max()
                     .unwrap_or(0);
-                if max_x == NUM_COLS - 1{
+                if max_x == self.board.cols - 1{
                     self.direction  = -1;
                     downwards = true;
                 }
             }
             if downwards {
-                let new_duration = max(self.move_timer.duration.as_millis() - 250, 250);
+                let new_duration = max(
+                    self.move_timer.duration.as_millis().saturating_sub(self.timing.speedup_ms as u128),
+                    self.timing.min_move_ms as u128,
+                );
                 self.move_timer = Timer::from_millis(new_duration as u64);
                 for invader in self.army.iter_mut(){
                     invader.y += 1;
@@ -84,40 +122,106 @@ impl Invaders {
         false
     }
 
+    fn update_bombs(&mut self, delta: Duration) {
+        for bomb in self.bombs.iter_mut() {
+            bomb.update(delta);
+        }
+        self.bombs.retain(|bomb| !bomb.dead());
+
+        self.fire_timer.update(delta);
+        if self.firing && self.fire_timer.ready {
+            self.fire_timer = Timer::from_millis(random_fire_ms(&self.timing, &mut self.rng));
+            self.drop_bomb();
+        }
+    }
+
+    /// Drops a bomb below the lowest invader of a randomly chosen column.
+    fn drop_bomb(&mut self) {
+        if self.army.is_empty() {
+            return;
+        }
+        let shooter = &self.army[self.rng.gen_range(0..self.army.len())];
+        let (x, y) = self.army.iter()
+            .filter(|invader| invader.x == shooter.x)
+            .map(|invader| (invader.x, invader.y))
+            .max_by_key(|(_, y)| *y)
+            .unwrap();
+        if y + 1 < self.board.rows {
+            self.next_bomb_id += 1;
+            self.bombs.push(Bomb::new(self.next_bomb_id, x, y + 1, self.board.rows - 1, self.timing.bomb_fall_ms));
+        }
+    }
+
+    /// Detonates a bomb sitting on the given cell, returning whether there was one.
+    pub fn bomb_hit_at(&mut self, x: usize, y: usize) -> bool {
+        match self.bombs.iter_mut().find(|bomb| !bomb.dead() && bomb.x == x && bomb.y == y) {
+            Some(bomb) => {
+                bomb.detonate();
+                true
+            },
+            None => false,
+        }
+    }
+
     pub fn all_killed(&self) -> bool {
         self.army.is_empty()
     }
 
     pub fn reached_bottom(&mut self) -> bool {
         self.stop = self.army.iter().map(|invader| invader.y)
-        .max().unwrap_or(0) >= NUM_ROWS - 2;
+        .max().unwrap_or(0) >= self.board.rows - 2;
         self.stop
     }
 
-    pub fn kill_invader_at(&mut self, x:usize, y:usize) -> bool{
-        if let Some(idx) = self.army.iter()
+    /// Damages the invader on the given cell, returning `None` when the cell is empty.
+    pub fn hit_invader_at(&mut self, x:usize, y:usize) -> Option<Hit> {
+        let idx = self.army.iter()
             .position(
                 |invader| (invader.x == x) && (invader.y == y)
-            )
-        {
-            self.army.remove(idx);
-            true
+            )?;
+        self.army[idx].hp = self.army[idx].hp.saturating_sub(1);
+        if self.army[idx].hp == 0 {
+            Some(Hit { killed: true, points: self.army.remove(idx).points })
         } else {
-            false
+            Some(Hit { killed: false, points: 0 })
+        }
+    }
+
+    /// Animation phase shared by the whole army: 0 while drawn as "x", 1 while drawn as "+".
+    pub fn phase(&self) -> u8 {
+        if self.move_timer.time_left.as_secs_f32() /
+        self.move_timer.duration.as_secs_f32() > 0.5 {
+            0
+        } else {
+            1
         }
     }
-    
 }
 
 impl Drawable for Invaders {
     fn draw(&self, frame: &mut Frame) {
+        let glyph = if self.phase() == 0 { "x" } else { "+" };
         for invader in self.army.iter(){
-            frame[invader.y][invader.x] = if self.move_timer.time_left.as_secs_f32() / 
-            self.move_timer.duration.as_secs_f32() > 0.5 {
-                "x"
-            } else {
-                "+"
-            }
+            frame[invader.y][invader.x] = glyph;
+        }
+        for bomb in self.bombs.iter().filter(|bomb| !bomb.dead()) {
+            bomb.draw(frame);
         }
     }
+}
+
+impl Snapshotable for Invaders {
+    fn snapshot(&self, entities: &mut Vec<Entity>) {
+        let phase = self.phase();
+        for invader in self.army.iter(){
+            entities.push(invader.entity(phase));
+        }
+        for bomb in self.bombs.iter().filter(|bomb| !bomb.dead()) {
+            bomb.snapshot(entities);
+        }
+    }
+}
+
+fn random_fire_ms(timing: &InvaderConfig, rng: &mut StdRng) -> u64 {
+    rng.gen_range(timing.fire_min_ms..=timing.fire_max_ms)
 }
\ No newline at end of file
@@ -0,0 +1,169 @@
+use std::{collections::{HashMap, HashSet}, fs, path::Path};
+use serde::Deserialize;
+use crate::config::InvaderConfig;
+use crate::game::common::Board;
+use crate::game::invaders::Invader;
+
+/// Rows left empty above a level's layout.
+const TOP_MARGIN: usize = 2;
+/// Rows kept free between the bottom of the layout and the losing line.
+const BOTTOM_MARGIN: usize = 3;
+const EMPTY_CELL: char = '.';
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct InvaderType {
+    pub name: String,
+    #[serde(default = "default_points")]
+    pub points: usize,
+    #[serde(default = "default_hp")]
+    pub hp: u8,
+}
+
+fn default_points() -> usize { 1 }
+fn default_hp() -> u8 { 1 }
+
+/// Timing fields a level file may set; anything left out falls back to `[game.invaders]`.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LevelTiming {
+    pub move_ms: Option<u64>,
+    pub min_move_ms: Option<u64>,
+    pub speedup_ms: Option<u64>,
+    pub fire_min_ms: Option<u64>,
+    pub fire_max_ms: Option<u64>,
+    pub bomb_fall_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Level {
+    pub name: String,
+    pub layout: Vec<String>,
+    pub invaders: HashMap<char, InvaderType>,
+    #[serde(default)]
+    pub timing: LevelTiming,
+}
+
+impl Level {
+    /// Loads every `.toml` and `.json` file in `dir`, sorted by file name, and validates each one.
+    pub fn load_dir(dir: &str, defaults: &InvaderConfig) -> Result<Vec<Level>, String> {
+        let entries = fs::read_dir(dir).map_err(|err| format!("unable to read level directory {dir}: {err}"))?;
+        let mut paths: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| matches!(path.extension().and_then(|ext| ext.to_str()), Some("toml") | Some("json")))
+            .collect();
+        paths.sort();
+
+        let mut levels: Vec<Level> = Vec::new();
+        for path in paths.iter() {
+            let level = Level::load(path)?;
+            level.validate(defaults).map_err(|err| format!("{}: {err}", path.display()))?;
+            if levels.iter().any(|other| other.name == level.name) {
+                return Err(format!("{}: duplicate level name {:?}", path.display(), level.name));
+            }
+            levels.push(level);
+        }
+        if levels.is_empty() {
+            return Err(format!("no level files found in {dir}"));
+        }
+        Ok(levels)
+    }
+
+    fn load(path: &Path) -> Result<Level, String> {
+        let contents = fs::read_to_string(path).map_err(|err| format!("{}: {err}", path.display()))?;
+        let parsed = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str::<Level>(&contents).map_err(|err| err.to_string()),
+            _ => toml::from_str::<Level>(&contents).map_err(|err| err.to_string()),
+        };
+        parsed.map_err(|err| format!("{}: {err}", path.display()))
+    }
+
+    pub fn width(&self) -> usize {
+        self.layout.iter().map(|row| row.chars().count()).max().unwrap_or(0)
+    }
+
+    pub fn height(&self) -> usize {
+        self.layout.len()
+    }
+
+    fn validate(&self, defaults: &InvaderConfig) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("name must not be empty".to_string());
+        }
+        if self.layout.is_empty() {
+            return Err("layout must have at least one row".to_string());
+        }
+        if self.invaders.contains_key(&EMPTY_CELL) {
+            return Err(format!("{EMPTY_CELL:?} marks an empty cell and cannot be an invader type"));
+        }
+        for (symbol, invader) in self.invaders.iter() {
+            if invader.hp == 0 {
+                return Err(format!("invader {symbol:?} must have at least 1 hp"));
+            }
+        }
+        let width = self.width();
+        let mut used = HashSet::new();
+        for (row, cells) in self.layout.iter().enumerate() {
+            if cells.chars().count() != width {
+                return Err(format!("layout row {} is {} wide, expected {width}", row + 1, cells.chars().count()));
+            }
+            for symbol in cells.chars().filter(|symbol| *symbol != EMPTY_CELL) {
+                if !self.invaders.contains_key(&symbol) {
+                    return Err(format!("layout row {} uses undefined invader {symbol:?}", row + 1));
+                }
+                used.insert(symbol);
+            }
+        }
+        if used.is_empty() {
+            return Err("layout must contain at least one invader".to_string());
+        }
+        let timing = self.timing(defaults);
+        if timing.move_ms == 0 || timing.min_move_ms == 0 || timing.min_move_ms > timing.move_ms {
+            return Err("timing.min_move_ms must be between 1 and timing.move_ms".to_string());
+        }
+        if timing.fire_min_ms == 0 || timing.fire_min_ms > timing.fire_max_ms {
+            return Err("timing.fire_min_ms must be between 1 and timing.fire_max_ms".to_string());
+        }
+        if timing.bomb_fall_ms == 0 {
+            return Err("timing.bomb_fall_ms must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// Checks the layout leaves room to march on the given board.
+    pub fn fits(&self, board: Board) -> Result<(), String> {
+        if self.width() > board.cols || TOP_MARGIN + self.height() + BOTTOM_MARGIN > board.rows {
+            return Err(format!(
+                "level {:?} needs a board of at least {}x{}, got {}x{}",
+                self.name, self.width(), TOP_MARGIN + self.height() + BOTTOM_MARGIN, board.cols, board.rows
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn timing(&self, defaults: &InvaderConfig) -> InvaderConfig {
+        InvaderConfig {
+            move_ms: self.timing.move_ms.unwrap_or(defaults.move_ms),
+            min_move_ms: self.timing.min_move_ms.unwrap_or(defaults.min_move_ms),
+            speedup_ms: self.timing.speedup_ms.unwrap_or(defaults.speedup_ms),
+            fire_min_ms: self.timing.fire_min_ms.unwrap_or(defaults.fire_min_ms),
+            fire_max_ms: self.timing.fire_max_ms.unwrap_or(defaults.fire_max_ms),
+            bomb_fall_ms: self.timing.bomb_fall_ms.unwrap_or(defaults.bomb_fall_ms),
+        }
+    }
+
+    /// Places the layout below the top margin, centered horizontally on the board.
+    pub fn spawn(&self, board: Board) -> Vec<Invader> {
+        let offset_x = (board.cols - self.width()) / 2;
+        let mut army = Vec::new();
+        for (row, cells) in self.layout.iter().enumerate() {
+            for (col, symbol) in cells.chars().enumerate() {
+                if let Some(invader) = self.invaders.get(&symbol) {
+                    army.push(Invader::new(army.len() + 1, offset_x + col, TOP_MARGIN + row, invader));
+                }
+            }
+        }
+        army
+    }
+}
@@ -2,4 +2,8 @@ pub mod frame;
 pub mod player;
 pub mod shot;
 pub mod common;
-pub mod invaders;
\ No newline at end of file
+pub mod invaders;
+pub mod snapshot;
+pub mod bomb;
+pub mod level;
+pub mod clock;
\ No newline at end of file
@@ -1,15 +1,76 @@
-use std::{fmt, time::Duration};
+use std::{collections::VecDeque, fmt, time::{Duration, Instant}};
 
+use rand::Rng;
+use rusty_time::timer::Timer;
+use serde::{Deserialize, Serialize};
 
-use crate::game::{frame::{Drawable, Frame}, shot::Shot, common::NUM_COLS, common::NUM_ROWS};
+use crate::game::{frame::{Drawable, Frame}, shot::Shot, common::Board};
+use crate::config::GameConfig;
 use crate::game::invaders::Invaders;
+use crate::game::snapshot::{Entity, PlayerSummary, Snapshotable};
+
+/// Consecutive hits needed to raise the score multiplier by one.
+const COMBO_STEP: usize = 5;
+const MAX_MULTIPLIER: usize = 4;
+
+/// A player's action, sent by the client as `{"type":"INPUT","action":"LEFT"}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    LEFT,
+    RIGHT,
+    FIRE,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PlayerStats {
+    pub score: usize,
+    pub kills: usize,
+    pub shots_fired: usize,
+    pub hits: usize,
+    pub combo: usize,
+    pub best_combo: usize,
+    pub survived: Duration,
+}
+
+impl PlayerStats {
+    /// Score multiplier earned by the current hit streak; a shot that misses resets it.
+    pub fn multiplier(&self) -> usize {
+        (1 + self.combo / COMBO_STEP).min(MAX_MULTIPLIER)
+    }
+
+    pub fn accuracy(&self) -> f32 {
+        if self.shots_fired == 0 {
+            0.0
+        } else {
+            self.hits as f32 / self.shots_fired as f32
+        }
+    }
+}
 
 pub struct Player {
     pub id: usize,
+    pub player_id: Option<String>,
+    pub name: String,
     pub x: usize,
     pub y:usize,
     pub shots: Vec<Shot>,
-    pub room_id: Option<String>
+    pub room_id: Option<String>,
+    pub board: Board,
+    pub lives: u8,
+    pub stats: PlayerStats,
+    /// Secret the client presents to take this slot back after its connection drops.
+    pub resume_token: String,
+    /// When the player's connection was lost; the slot is held until the grace period runs out.
+    pub disconnected: Option<Instant>,
+    /// Inputs waiting for the next tick, with the client's sequence number.
+    inputs: VecDeque<(Input, Option<u64>)>,
+    /// Sequence number of the last input applied.
+    pub last_seq: Option<u64>,
+    inputs_per_tick: usize,
+    input_buffer: usize,
+    spawn_x: usize,
+    invulnerable: Timer,
+    next_shot_id: usize,
 }
 
 
@@ -21,19 +82,71 @@ impl fmt::Debug for Player {
 
 
 impl Player {
-    pub fn new(id: usize) -> Self {
+    /// Spawns the player in its own lane, spreading `lanes` slots evenly across the bottom row.
+    pub fn new(id: usize, lane: usize, lanes: usize, config: &GameConfig) -> Self {
+        let board = config.board;
+        let spawn_x = ((lane + 1) * board.cols / (lanes + 1)).min(board.cols - 1);
         Self {
-            id: id,
-            x: NUM_COLS / 2,
-            y: NUM_ROWS -1,
+            id,
+            player_id: None,
+            name: format!("Player {}", lane + 1),
+            x: spawn_x,
+            y: board.rows -1,
             shots: Vec::new(),
             room_id: None,
+            board,
+            lives: config.player.lives,
+            stats: PlayerStats::default(),
+            resume_token: format!("{:032x}", rand::thread_rng().gen::<u128>()),
+            disconnected: None,
+            inputs: VecDeque::new(),
+            last_seq: None,
+            inputs_per_tick: config.player.inputs_per_tick,
+            input_buffer: config.player.input_buffer,
+            spawn_x,
+            invulnerable: Timer::from_millis(config.player.invulnerable_ms),
+            next_shot_id: 0,
         }
     }
 
-    pub fn move_up(&mut self){
-        if self.y > 0 {
-            self.y -= 1;
+    pub fn is_out(&self) -> bool {
+        self.lives == 0
+    }
+
+    pub fn is_invulnerable(&self) -> bool {
+        !self.invulnerable.ready
+    }
+
+    /// Costs a life unless the player is still invulnerable from its last respawn.
+    /// Returns true when this hit took the player's last life.
+    pub fn hit(&mut self) -> bool {
+        if self.is_out() || self.is_invulnerable() {
+            return false;
+        }
+        self.lives -= 1;
+        if self.is_out() {
+            return true;
+        }
+        self.x = self.spawn_x;
+        self.invulnerable.reset();
+        false
+    }
+
+    /// Returns true when a bomb took the player's last life.
+    pub fn detect_bomb_hits(&mut self, invaders: &mut Invaders) -> bool {
+        if self.is_out() || !invaders.bomb_hit_at(self.x, self.y) {
+            return false;
+        }
+        self.hit()
+    }
+
+    /// Hands the ship over to a new session after a reconnect.
+    pub fn resume(&mut self, session_id: usize) {
+        self.id = session_id;
+        self.disconnected = None;
+        self.inputs.clear();
+        for shot in self.shots.iter_mut() {
+            shot.owner = session_id;
         }
     }
 
@@ -44,49 +157,92 @@ impl Player {
     }
 
     pub fn move_right(&mut self){
-        if self.x < NUM_COLS - 1{
+        if self.x < self.board.cols - 1{
             self.x += 1;
         }
     }
 
     pub fn shoot(&mut self) -> bool {
         if self.shots.len() < 20 {
-            self.shots.push(Shot::new(self.x, self.y - 1));
+            self.next_shot_id += 1;
+            self.shots.push(Shot::new(self.next_shot_id, self.id, self.x, self.y - 1));
+            self.stats.shots_fired += 1;
             true
         } else {
             false
         }
     }
 
-    pub fn handle_movement(&mut self, movement: &str) -> bool {
-        match movement {
-            "-1" => self.move_left(),
-            "1" => self.move_right(),
-            "-" => {
+    /// Queues an input for the next tick; refused while the queue is full.
+    pub fn queue_input(&mut self, input: Input, seq: Option<u64>) -> Result<(), String> {
+        if self.inputs.len() >= self.input_buffer {
+            return Err("too many inputs queued, slow down".to_string());
+        }
+        self.inputs.push_back((input, seq));
+        Ok(())
+    }
+
+    /// Applies up to `inputs_per_tick` queued inputs, oldest first, and returns them.
+    pub fn apply_inputs(&mut self) -> Vec<Input> {
+        let mut applied = Vec::new();
+        for _ in 0..self.inputs_per_tick {
+            let Some((input, seq)) = self.inputs.pop_front() else {
+                break;
+            };
+            self.handle_input(input);
+            if seq.is_some() {
+                self.last_seq = seq;
+            }
+            applied.push(input);
+        }
+        applied
+    }
+
+    pub fn handle_input(&mut self, input: Input) -> bool {
+        if self.is_out() {
+            return false;
+        }
+        match input {
+            Input::LEFT => self.move_left(),
+            Input::RIGHT => self.move_right(),
+            Input::FIRE => {
                 self.shoot();
-                ()
             },
-            _ => println!("[ERROR] handle_movement invalid movement {}", movement)
         }
         true
     }
 
-pub fn detect_hits(&mut self, invaders: &mut Invaders) -> usize{
-        let mut nb_killed = 0;
-        for shot in self.shots.iter_mut() {
-            if invaders.kill_invader_at(shot.x, shot.y){
-                nb_killed += 1;
+    /// Returns the points earned by shots that landed this tick, boosted by the combo multiplier.
+    pub fn detect_hits(&mut self, invaders: &mut Invaders) -> usize{
+        let mut points = 0;
+        for shot in self.shots.iter_mut().filter(|shot| !shot.exploding) {
+            if let Some(hit) = invaders.hit_invader_at(shot.x, shot.y){
+                self.stats.hits += 1;
+                self.stats.combo += 1;
+                self.stats.best_combo = self.stats.best_combo.max(self.stats.combo);
+                if hit.killed {
+                    self.stats.kills += 1;
+                }
+                points += hit.points * self.stats.multiplier();
                 shot.explode();
             }
         }
         self.shots.retain(|shot| !shot.dead());
-        nb_killed        
+        self.stats.score += points;
+        points
     }
 
     pub fn update(&mut self, delta: Duration) {
+        self.invulnerable.update(delta);
+        if !self.is_out() {
+            self.stats.survived += delta;
+        }
         for shot in self.shots.iter_mut(){
             shot.update(delta);
         }
+        if self.shots.iter().any(|shot| shot.dead() && !shot.exploding) {
+            self.stats.combo = 0;
+        }
         self.shots.retain(|shot| !shot.dead());
     }
     
@@ -94,9 +250,49 @@ pub fn detect_hits(&mut self, invaders: &mut Invaders) -> usize{
 
 impl Drawable for Player {
     fn draw(&self, frame: &mut Frame){
-        frame[self.y][self.x] = "A";
+        if !self.is_out() {
+            frame[self.y][self.x] = "A";
+        }
         for shot in self.shots.iter() {
             shot.draw(frame);
         }
     }
-}
\ No newline at end of file
+}
+
+impl Player {
+    pub fn summary(&self) -> PlayerSummary {
+        PlayerSummary {
+            id: self.id,
+            player_id: self.player_id.clone(),
+            name: self.name.clone(),
+            score: self.stats.score,
+            kills: self.stats.kills,
+            shots_fired: self.stats.shots_fired,
+            hits: self.stats.hits,
+            accuracy: self.stats.accuracy(),
+            best_combo: self.stats.best_combo,
+            survived_ms: self.stats.survived.as_millis(),
+            lives: self.lives,
+        }
+    }
+}
+
+impl Snapshotable for Player {
+    fn snapshot(&self, entities: &mut Vec<Entity>) {
+        if !self.is_out() {
+            entities.push(Entity::PLAYER {
+                id: self.id,
+                x: self.x,
+                y: self.y,
+                lives: self.lives,
+                invulnerable: self.is_invulnerable(),
+                score: self.stats.score,
+                multiplier: self.stats.multiplier(),
+                seq: self.last_seq,
+            });
+        }
+        for shot in self.shots.iter() {
+            shot.snapshot(entities);
+        }
+    }
+}
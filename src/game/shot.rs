@@ -3,8 +3,11 @@ use std::time::Duration;
 use rusty_time::timer::Timer;
 
 use crate::game::frame::Drawable;
+use crate::game::snapshot::{Entity, Snapshotable};
 
 pub struct Shot {
+    pub id: usize,
+    pub owner: usize,
     pub x: usize,
     pub y: usize,
     pub exploding: bool,
@@ -12,8 +15,10 @@ pub struct Shot {
 }
 
 impl Shot {
-    pub fn new(x: usize, y: usize) -> Self {
+    pub fn new(id: usize, owner: usize, x: usize, y: usize) -> Self {
         Self {
+            id,
+            owner,
             x,
             y,
             exploding: false,
@@ -42,4 +47,16 @@ impl Drawable for Shot {
     fn draw(&self, frame: &mut crate::game::frame::Frame) {
         frame[self.y][self.x] = if self.exploding {"*"} else {"|"}
     }
-}
\ No newline at end of file
+}
+
+impl Snapshotable for Shot {
+    fn snapshot(&self, entities: &mut Vec<Entity>) {
+        entities.push(Entity::SHOT {
+            id: self.id,
+            owner: self.owner,
+            x: self.x,
+            y: self.y,
+            exploding: self.exploding,
+        });
+    }
+}
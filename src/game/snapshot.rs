@@ -0,0 +1,121 @@
+use serde::Serialize;
+use crate::game::player::Input;
+use crate::game_session::GameStateType;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "kind")]
+pub enum Entity {
+    PLAYER {
+        id: usize,
+        x: usize,
+        y: usize,
+        lives: u8,
+        invulnerable: bool,
+        score: usize,
+        multiplier: usize,
+        /// Sequence number of the last input applied, for client-side prediction.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    SHOT {
+        id: usize,
+        owner: usize,
+        x: usize,
+        y: usize,
+        exploding: bool,
+    },
+    INVADER {
+        id: usize,
+        class: String,
+        x: usize,
+        y: usize,
+        hp: u8,
+        phase: u8,
+    },
+    BOMB {
+        id: usize,
+        x: usize,
+        y: usize,
+    },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Snapshot {
+    pub tick: u64,
+    pub state: GameStateType,
+    pub score: usize,
+    pub wave: usize,
+    pub intermission: bool,
+    pub entities: Vec<Entity>,
+}
+
+impl Snapshot {
+    pub fn new(tick: u64, state: GameStateType, score: usize, wave: usize) -> Snapshot {
+        Snapshot { tick, state, score, wave, intermission: false, entities: Vec::new() }
+    }
+
+    /// Sequence number of the last input applied for the player on `session_id`.
+    pub fn seq_of(&self, session_id: usize) -> Option<u64> {
+        self.entities.iter().find_map(|entity| match entity {
+            Entity::PLAYER { id, seq, .. } if *id == session_id => *seq,
+            _ => None,
+        })
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PlayerSummary {
+    pub id: usize,
+    /// Persistent id from the player's token; `None` for anonymous sessions.
+    pub player_id: Option<String>,
+    pub name: String,
+    pub score: usize,
+    pub kills: usize,
+    pub shots_fired: usize,
+    pub hits: usize,
+    pub accuracy: f32,
+    pub best_combo: usize,
+    pub survived_ms: u128,
+    pub lives: u8,
+}
+
+/// Something the simulation does not derive from its seed, stamped with the tick it took effect on.
+/// Applying each change just before its tick replays the game.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct ReplayEvent {
+    pub tick: u64,
+    #[serde(flatten)]
+    pub change: ReplayChange,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "kind")]
+pub enum ReplayChange {
+    INPUT { slot: usize, action: Input },
+    PAUSE { paused: bool },
+    /// A new ship was seated in the slot.
+    JOIN { slot: usize },
+    LEAVE { slot: usize },
+    /// The slot's connection dropped and the ship is held for a resume.
+    HOLD { slot: usize },
+    RESUME { slot: usize },
+}
+
+/// Sent once when a game is won or lost.
+#[derive(Serialize, Debug, Clone)]
+pub struct GameSummary {
+    pub state: GameStateType,
+    /// Seed the game ran with; replaying `replay` on it gives the same game.
+    pub seed: u64,
+    pub score: usize,
+    pub wave: usize,
+    pub duration_ms: u128,
+    pub players: Vec<PlayerSummary>,
+    /// Stored with the leaderboard record but never sent to clients.
+    #[serde(skip)]
+    pub replay: Vec<ReplayEvent>,
+}
+
+pub trait Snapshotable {
+    fn snapshot(&self, entities: &mut Vec<Entity>);
+}
@@ -1,36 +1,58 @@
 use std::sync::{Arc, Mutex, RwLock};
 use std::fmt;
-use actix::Addr;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use tokio::time::Duration;
-use std::ops::{Deref, DerefMut};
-use crate::game::common::NUM_COLS;
+use std::ops::DerefMut;
+use crate::config::GameConfig;
 use crate::game::invaders::Invaders;
+use rusty_time::timer::Timer;
 use crate::game::{frame::{Drawable, Frame}, player::Player};
+use crate::game::snapshot::{GameSummary, ReplayChange, ReplayEvent, Snapshot, Snapshotable};
+use serde::Serialize;
 use crate::server::GameSessionMessage;
 
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize)]
 pub enum GameStateType {
     IDLE,
     START,
     STOP,
     WIN,
-    LOSE
+    LOSE,
+    PAUSE,
 }
 
 pub struct GameSession{
     pub room: String,
-    pub last_frame: Arc<Mutex<Option<Frame>>>,
-    pub player1: Option<Arc<Mutex<Player>>>,
-    pub player2: Option<Arc<Mutex<Player>>>,
+    pub last_frame: Arc<Frame>,
+    pub players: Vec<Option<Arc<Mutex<Player>>>>,
     pub invaders: Option<Arc<Mutex<Invaders>>>,
     pub state: Arc<RwLock<GameStateType>>,
-    pub server_addr: Addr<crate::server::ChatServer>,
-    pub player1_sessionid: usize,
-    pub player2_sessionid: usize,
     pub score: usize,
+    pub tick: u64,
+    pub elapsed: Duration,
+    pub wave: usize,
+    pub config: GameConfig,
+    /// Index into `config.levels` the room picked; `None` cycles through every level.
+    pub level: Option<usize>,
+    intermission: Option<Timer>,
+    announced_wave: usize,
+    /// Runs between everyone readying up in the lobby and the first wave.
+    countdown: Option<Timer>,
+    announced_countdown: Option<u64>,
+    /// Every random choice in the game comes from this, so a seed replays the same game.
+    pub seed: u64,
+    rng: StdRng,
+    /// Every input applied and every pause and seat change so far, enough to replay the game from `seed`.
+    pub log: Vec<ReplayEvent>,
+    /// Clock reading at the last `advance`, and time since then not yet simulated.
+    last_clock: Option<Duration>,
+    lag: Duration,
 }
 
+/// Ticks `advance` runs at most in one call; a loop that fell further behind skips the rest.
+const MAX_CATCH_UP_TICKS: u32 = 5;
+
 impl fmt::Debug for GameSession {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Game session started")
@@ -38,84 +60,248 @@ impl fmt::Debug for GameSession {
 }
 
 impl GameSession{
+    #[allow(dead_code)]
     pub fn render(&self){
-        if let Some(frame) = self.last_frame.lock().unwrap().deref() {
-            for row in frame{
-                for col in row {
-                    print!("{}", &col)
-                }
-                println!()
+        for row in self.last_frame.iter() {
+            for col in row {
+                print!("{}", &col)
             }
+            println!()
         }
         println!();
-        for _ in 0..NUM_COLS {
+        for _ in 0..self.config.board.cols {
             print!("=")
         }
         println!();
     }
 
-    pub fn new(room: String, server_addr: Addr<crate::server::ChatServer>) -> GameSession{
-
-        let initial_frame = Arc::new(Mutex::new(Some(crate::game::frame::new_frame())));
+    pub fn new(room: String, capacity: usize, config: GameConfig, seed: u64) -> GameSession{
 
-        server_addr.do_send(GameSessionMessage{
-            frame: initial_frame.clone(),
-            room_id: room.clone(),
-            state:GameStateType::START,
-            player1_sessionid: 0,
-            player2_sessionid: 0
-        });
+        let initial_frame = Arc::new(crate::game::frame::new_frame(config.board));
 
-
-        let gs =  GameSession{
-            server_addr: server_addr,
+        GameSession{
             room: room.clone(),
-            last_frame: initial_frame.clone(),
-            player1: None,
-            player2: None,
+            last_frame: initial_frame,
+            players: vec![None; capacity],
             invaders: None,
-            player1_sessionid: 0,
-            player2_sessionid: 0,
             state: Arc::new(RwLock::new(GameStateType::IDLE)),
             score: 0,
+            tick: 0,
+            elapsed: Duration::ZERO,
+            wave: 0,
+            config,
+            level: None,
+            intermission: None,
+            announced_wave: 0,
+            countdown: None,
+            announced_countdown: None,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            log: Vec::new(),
+            last_clock: None,
+            lag: Duration::ZERO,
+        }
+    }
+
+    /// Replaces the army with the level for `wave`, using that wave's speed and fire rate.
+    pub fn start_wave(&mut self, wave: usize){
+        let levels = self.config.levels.clone();
+        let level = &levels[self.level.unwrap_or((wave.max(1) - 1) % levels.len())];
+        let invaders = Invaders::new(
+            self.config.board,
+            self.config.timing_for_wave(level, wave),
+            level.spawn(self.config.board),
+            wave >= self.config.waves.fire_from_wave,
+            StdRng::seed_from_u64(self.rng.gen()),
+        );
+        println!("[INFO] Room [{}] wave {} started on level {}", self.room, wave, level.name);
+        self.wave = wave;
+        self.invaders = Some(Arc::new(Mutex::new(invaders)));
+    }
+
+    pub fn player_session_ids(&self) -> Vec<usize> {
+        self.players.iter()
+            .flatten()
+            .map(|player| player.lock().unwrap().id)
+            .collect()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(*self.state.read().unwrap(), GameStateType::WIN | GameStateType::LOSE | GameStateType::STOP)
+    }
+
+    /// True from the end of the countdown until the game is won, lost or stopped, including while paused.
+    pub fn is_playing(&self) -> bool {
+        matches!(*self.state.read().unwrap(), GameStateType::START | GameStateType::PAUSE)
+    }
+
+    pub fn counting_down(&self) -> bool {
+        self.countdown.is_some()
+    }
+
+    pub fn start_countdown(&mut self) {
+        self.countdown = Some(Timer::from_millis(self.config.countdown_secs * 1000));
+        self.announced_countdown = None;
+    }
+
+    pub fn cancel_countdown(&mut self) {
+        self.countdown = None;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        matches!(*self.state.read().unwrap(), GameStateType::PAUSE)
+    }
+
+    /// Freezes or unfreezes the simulation. Paused ticks still go out so clients keep getting frames.
+    pub fn set_paused(&mut self, paused: bool) {
+        let next = match (&*self.state.read().unwrap(), paused) {
+            (GameStateType::START, true) => GameStateType::PAUSE,
+            (GameStateType::PAUSE, false) => GameStateType::START,
+            _ => return,
         };
+        *self.state.write().unwrap() = next;
+        self.record(ReplayChange::PAUSE { paused });
+    }
+
+    /// Puts `player` in `slot`, replacing whoever was there.
+    pub fn seat(&mut self, slot: usize, player: Arc<Mutex<Player>>) {
+        self.players[slot] = Some(player);
+        self.record(ReplayChange::JOIN { slot });
+    }
 
-        gs
+    pub fn unseat(&mut self, slot: usize) {
+        if self.players[slot].take().is_some() {
+            self.record(ReplayChange::LEAVE { slot });
+        }
     }
-    pub fn update_frame(&mut self, delta: Duration){
-        let mut new_frame = crate::game::frame::new_frame();
-        if let Some(p1) = &self.player1 {
-            p1.lock().unwrap().update(delta);
-            p1.lock().unwrap().draw(&mut new_frame);
+
+    /// Logs a change made between ticks; it takes effect on the next one.
+    pub fn record(&mut self, change: ReplayChange) {
+        self.log.push(ReplayEvent { tick: self.tick + 1, change });
+    }
+
+    /// Ends the game where it stands; a stopped game never resumes.
+    pub fn stop(&mut self) {
+        *self.state.write().unwrap() = GameStateType::STOP;
+    }
+
+    pub fn summary(&self) -> GameSummary {
+        GameSummary {
+            state: self.state.read().unwrap().clone(),
+            seed: self.seed,
+            score: self.score,
+            wave: self.wave,
+            duration_ms: self.elapsed.as_millis(),
+            players: self.players.iter()
+                .flatten()
+                .map(|player| player.lock().unwrap().summary())
+                .collect(),
+            replay: self.log.clone(),
         }
+    }
 
-        if let Some(p2) = &self.player2 {
-            p2.lock().unwrap().update(delta);
-            p2.lock().unwrap().draw(&mut new_frame);
+    /// Runs every fixed-length tick that is due by `now`, a reading of the room's clock,
+    /// and returns the update each of them produced for the server to send out, oldest first.
+    pub fn advance(&mut self, now: Duration) -> Vec<GameSessionMessage> {
+        let last = self.last_clock.replace(now).unwrap_or(now);
+        self.lag += now.saturating_sub(last);
+        let tick = self.config.tick();
+        let mut updates = Vec::new();
+        let mut steps = 0;
+        while self.lag >= tick {
+            self.lag -= tick;
+            updates.extend(self.update_frame(tick));
+            steps += 1;
+            if steps == MAX_CATCH_UP_TICKS {
+                self.lag = Duration::ZERO;
+            }
+        }
+        updates
+    }
+
+    /// Forgets the last clock reading, so time spent with the loop stopped is not simulated.
+    pub fn restart_clock(&mut self) {
+        self.last_clock = None;
+        self.lag = Duration::ZERO;
+    }
+
+    /// Advances the game by one tick of length `delta`; only ever called with `config.tick()`,
+    /// so the same seed and inputs always give the same frames. Finished games produce nothing.
+    fn update_frame(&mut self, delta: Duration) -> Option<GameSessionMessage> {
+        if self.is_finished() {
+            return None;
+        }
+        if let Some(countdown) = &mut self.countdown {
+            countdown.update(delta);
+            if !countdown.ready {
+                let secs = (countdown.time_left.as_millis() as u64).div_ceil(1000);
+                let announce = (self.announced_countdown != Some(secs)).then_some(secs);
+                self.announced_countdown = Some(secs);
+                let state = self.state.read().unwrap().clone();
+                return Some(GameSessionMessage{
+                    frame: self.last_frame.clone(),
+                    room_id: self.room.clone(),
+                    snapshot: Arc::new(Snapshot::new(self.tick, state.clone(), self.score, self.wave)),
+                    state,
+                    player_session_ids: self.player_session_ids(),
+                    eliminated: Vec::new(),
+                    wave_started: None,
+                    countdown: announce,
+                    summary: None,
+                });
+            }
+            self.countdown = None;
+            *self.state.write().unwrap() = GameStateType::START;
+            self.start_wave(1);
+        }
+        // Timers only advance by `delta`, so a zero delta freezes invaders, shots, bombs and the intermission.
+        let delta = if self.is_paused() { Duration::ZERO } else { delta };
+        self.tick += 1;
+        self.elapsed += delta;
+        let mut new_frame = crate::game::frame::new_frame(self.config.board);
+        let mut entities = Vec::new();
+        for (slot, player) in self.players.iter().enumerate() {
+            let Some(player) = player else {
+                continue;
+            };
+            if !self.is_paused() {
+                for action in player.lock().unwrap().apply_inputs() {
+                    self.log.push(ReplayEvent { tick: self.tick, change: ReplayChange::INPUT { slot, action } });
+                }
+            }
+            player.lock().unwrap().update(delta);
+            player.lock().unwrap().draw(&mut new_frame);
+            player.lock().unwrap().snapshot(&mut entities);
         }
 
         if let Some(invaders) = &self.invaders {
             invaders.lock().unwrap().update(delta);
             invaders.lock().unwrap().draw(&mut new_frame);
+            invaders.lock().unwrap().snapshot(&mut entities);
         }
 
-        if let Some(p1) = &self.player1 {
-            if let Some(invaders) = &self.invaders {
-                self.score += p1.lock().unwrap().detect_hits(invaders.lock().unwrap().deref_mut()); 
+        let mut eliminated = Vec::new();
+        if let Some(invaders) = &self.invaders {
+            for player in self.players.iter().flatten() {
+                let mut player = player.lock().unwrap();
+                self.score += player.detect_hits(invaders.lock().unwrap().deref_mut());
+                if player.detect_bomb_hits(invaders.lock().unwrap().deref_mut()) {
+                    println!("[INFO] Room [{}] Player {} is out of lives", self.room, player.id);
+                    eliminated.push(player.id);
+                }
             }
         }
 
-        if let Some(p2) = &self.player2 {
-            if let Some(invaders) = &self.invaders {
-                self.score += p2.lock().unwrap().detect_hits(invaders.lock().unwrap().deref_mut()); 
-            }
+        let mut players = self.players.iter().flatten().peekable();
+        if players.peek().is_some() && players.all(|player| player.lock().unwrap().is_out()) {
+            let mut state = self.state.write().unwrap();
+            *state = GameStateType::LOSE;
         }
 
-
+        let mut cleared = false;
         if let Some(invaders) = &self.invaders {
-            if invaders.lock().unwrap().all_killed(){
-                let mut state = self.state.write().unwrap();
-                *state = GameStateType::WIN;
+            if invaders.lock().unwrap().all_killed() && self.intermission.is_none() {
+                cleared = true;
             }
 
             if invaders.lock().unwrap().reached_bottom(){
@@ -124,22 +310,161 @@ impl GameSession{
             }
         }
         
-        {
-            let mut binding = self.last_frame.lock().unwrap();    
-            let last_frame = binding.deref_mut();    
-            *last_frame = Some(new_frame);    
+        if cleared {
+            if self.wave >= self.config.waves.count {
+                let mut state = self.state.write().unwrap();
+                *state = GameStateType::WIN;
+            } else {
+                println!("[INFO] Room [{}] wave {} cleared", self.room, self.wave);
+                self.intermission = Some(Timer::from_millis(self.config.waves.intermission_ms));
+            }
+        }
+
+        if let Some(intermission) = &mut self.intermission {
+            intermission.update(delta);
+            if intermission.ready {
+                self.intermission = None;
+                self.start_wave(self.wave + 1);
+            }
+        }
+
+        let mut wave_started = None;
+        if self.wave != self.announced_wave {
+            self.announced_wave = self.wave;
+            wave_started = Some(self.wave);
         }
 
+        self.last_frame = Arc::new(new_frame);
+
         //let frame_json_binding = self.last_frame.lock().unwrap();
         //let frame_json = serde_json::to_string(frame_json_binding.deref()).unwrap();
         //self.render();
         
-        self.server_addr.do_send(GameSessionMessage{
+        let state = self.state.read().unwrap().clone();
+        let summary = if self.is_finished() { Some(Arc::new(self.summary())) } else { None };
+        let mut snapshot = Snapshot::new(self.tick, state.clone(), self.score, self.wave);
+        snapshot.intermission = self.intermission.is_some();
+        snapshot.entities = entities;
+
+        Some(GameSessionMessage{
             frame: self.last_frame.clone(),
             room_id: self.room.clone(),
-            state: self.state.read().unwrap().clone(),
-            player1_sessionid: self.player1_sessionid,
-            player2_sessionid: self.player2_sessionid
-        });
+            state,
+            player_session_ids: self.player_session_ids(),
+            eliminated,
+            wave_started,
+            snapshot: Arc::new(snapshot),
+            countdown: None,
+            summary,
+        })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::level::Level;
+    use crate::game::player::Input;
+
+    const TICKS: u64 = 300;
+
+    /// Inputs from both slots, with a pause from tick 60 to 80 that some of them land in.
+    fn script() -> Vec<ReplayEvent> {
+        let mut events = Vec::new();
+        for tick in (20..TICKS / 2).step_by(7) {
+            let action = match tick % 3 {
+                0 => Input::LEFT,
+                1 => Input::RIGHT,
+                _ => Input::FIRE,
+            };
+            events.push(ReplayEvent { tick, change: ReplayChange::INPUT { slot: 0, action } });
+            events.push(ReplayEvent { tick, change: ReplayChange::INPUT { slot: 1, action: Input::FIRE } });
+        }
+        events.push(ReplayEvent { tick: 60, change: ReplayChange::PAUSE { paused: true } });
+        events.push(ReplayEvent { tick: 80, change: ReplayChange::PAUSE { paused: false } });
+        events.sort_by_key(|event| event.tick);
+        events
+    }
+
+    fn empty_session(seed: u64) -> GameSession {
+        let mut config = GameConfig { countdown_secs: 1, ..GameConfig::default() };
+        config.waves.fire_from_wave = 1;
+        config.levels = Arc::new(Level::load_dir("levels", &config.invaders).unwrap());
+        let mut session = GameSession::new("replay".to_string(), 2, config, seed);
+        session.start_countdown();
+        session
+    }
+
+    fn new_session(seed: u64) -> GameSession {
+        let mut session = empty_session(seed);
+        for slot in 0..2 {
+            apply(&mut session, ReplayChange::JOIN { slot });
+        }
+        session
+    }
+
+    fn apply(session: &mut GameSession, change: ReplayChange) {
+        match change {
+            ReplayChange::INPUT { slot, action } => {
+                let player = session.players[slot].as_ref().unwrap();
+                player.lock().unwrap().queue_input(action, None).unwrap();
+            },
+            ReplayChange::PAUSE { paused } => session.set_paused(paused),
+            ReplayChange::JOIN { slot } => {
+                let player = Player::new(slot + 1, slot, session.players.len(), &session.config);
+                session.seat(slot, Arc::new(Mutex::new(player)));
+            },
+            ReplayChange::LEAVE { slot } => session.unseat(slot),
+            ReplayChange::HOLD { .. } | ReplayChange::RESUME { .. } => session.record(change),
+        }
+    }
+
+    /// Steps the game one tick at a time, applying each change just before the tick it is stamped with,
+    /// and returns every frame and snapshot the game produced.
+    fn run(session: &mut GameSession, events: &[ReplayEvent]) -> Vec<(Arc<Frame>, String)> {
+        let tick = session.config.tick();
+        let mut events = events.iter().peekable();
+        let mut now = Duration::ZERO;
+        let mut updates = session.advance(now);
+        for _ in 0..TICKS {
+            while let Some(event) = events.next_if(|event| event.tick == session.tick + 1) {
+                apply(session, event.change);
+            }
+            now += tick;
+            updates.extend(session.advance(now));
+        }
+        updates.into_iter()
+            .map(|update| (update.frame, serde_json::to_string(update.snapshot.as_ref()).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_and_inputs_replay_the_same_game() {
+        let script = script();
+        let mut first = new_session(42);
+        let mut second = new_session(42);
+        let first_run = run(&mut first, &script);
+        let second_run = run(&mut second, &script);
+
+        assert_eq!(first_run.len(), TICKS as usize);
+        assert!(first_run == second_run);
+        assert_eq!(first.summary().replay, second.summary().replay);
+
+        let mut other = new_session(7);
+        assert!(run(&mut other, &script) != first_run);
+    }
+
+    #[test]
+    fn the_log_replays_a_game_with_a_pause() {
+        let mut original = new_session(42);
+        let original_run = run(&mut original, &script());
+        let pauses: Vec<_> = original.log.iter()
+            .filter_map(|event| matches!(event.change, ReplayChange::PAUSE { .. }).then_some(event.tick))
+            .collect();
+        assert_eq!(pauses, vec![60, 80]);
+
+        let mut replay = empty_session(42);
+        let log = original.log.clone();
+        assert!(run(&mut replay, &log) == original_run);
+        assert_eq!(replay.log, log);
+    }
+}
@@ -0,0 +1,257 @@
+use std::{sync::Mutex, time::{SystemTime, UNIX_EPOCH}};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use crate::game::snapshot::GameSummary;
+
+pub const DEFAULT_LIMIT: usize = 10;
+pub const MAX_LIMIT: usize = 100;
+/// Recent games averaged into a player's rating.
+const RATING_GAMES: i64 = 10;
+/// Stored in SQLite's `user_version`; bumped whenever an existing file needs `migrate`.
+const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS games (
+        id INTEGER PRIMARY KEY,
+        room TEXT NOT NULL,
+        state TEXT NOT NULL,
+        score INTEGER NOT NULL,
+        wave INTEGER NOT NULL,
+        duration_ms INTEGER NOT NULL,
+        finished_at INTEGER NOT NULL
+    );
+    -- player_id is NULL for anonymous sessions, which have no id that outlives the connection.
+    CREATE TABLE IF NOT EXISTS game_players (
+        game_id INTEGER NOT NULL REFERENCES games(id),
+        player_id TEXT,
+        name TEXT NOT NULL,
+        score INTEGER NOT NULL,
+        kills INTEGER NOT NULL,
+        accuracy REAL NOT NULL,
+        survived_ms INTEGER NOT NULL
+    );
+    -- The seed (as its i64 bit pattern) and JSON replay log that reproduce a game under the same config.
+    CREATE TABLE IF NOT EXISTS game_replays (
+        game_id INTEGER PRIMARY KEY REFERENCES games(id),
+        seed INTEGER NOT NULL,
+        events TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS game_players_score ON game_players(score DESC);
+    CREATE INDEX IF NOT EXISTS games_finished_at ON games(finished_at);
+    CREATE INDEX IF NOT EXISTS games_room ON games(room);
+";
+
+#[derive(Serialize, Debug)]
+pub struct Entry {
+    pub name: String,
+    /// `None` for anonymous players.
+    pub player_id: Option<String>,
+    pub score: usize,
+    pub kills: usize,
+    pub accuracy: f32,
+    pub wave: usize,
+    pub room: String,
+    pub duration_ms: u64,
+    pub finished_at: u64,
+}
+
+pub enum Scope {
+    Overall,
+    /// The current UTC calendar day.
+    Today,
+    /// A UTC calendar day formatted `YYYY-MM-DD`.
+    Day(String),
+    Room(String),
+}
+
+/// Finished games kept in an embedded SQLite file.
+#[derive(Debug)]
+pub struct Leaderboard {
+    conn: Mutex<Connection>,
+}
+
+impl Leaderboard {
+    pub fn open(path: &str) -> Result<Leaderboard, String> {
+        let conn = Connection::open(path).map_err(|err| format!("unable to open leaderboard {path}: {err}"))?;
+        conn.execute_batch(SCHEMA).map_err(|err| format!("unable to create leaderboard schema: {err}"))?;
+        Leaderboard::migrate(&conn).map_err(|err| format!("unable to migrate leaderboard {path}: {err}"))?;
+        Ok(Leaderboard { conn: Mutex::new(conn) })
+    }
+
+    fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version < 1 {
+            // Anonymous players used to be stored under their session id, which restarts at 1 with
+            // every process, merging unrelated guests. Token ids are 32 hex digits; clear the rest.
+            conn.execute_batch("
+                BEGIN;
+                ALTER TABLE game_players RENAME TO game_players_v0;
+                DROP INDEX IF EXISTS game_players_score;
+            ")?;
+            conn.execute_batch(SCHEMA)?;
+            conn.execute_batch("
+                INSERT INTO game_players (game_id, player_id, name, score, kills, accuracy, survived_ms)
+                    SELECT game_id, CASE WHEN length(player_id) = 32 THEN player_id END, name, score, kills, accuracy, survived_ms
+                    FROM game_players_v0;
+                DROP TABLE game_players_v0;
+                COMMIT;
+            ")?;
+        }
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)
+    }
+
+    /// Checks `day` looks like `YYYY-MM-DD` so it can be compared against stored dates.
+    pub fn is_day(day: &str) -> bool {
+        let bytes = day.as_bytes();
+        bytes.len() == 10 && bytes.iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+    }
+
+    pub fn record(&self, room: &str, summary: &GameSummary) -> Result<(), String> {
+        let finished_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let state = serde_json::to_value(&summary.state).ok()
+            .and_then(|value| value.as_str().map(str::to_string))
+            .unwrap_or_default();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|err| err.to_string())?;
+        tx.execute(
+            "INSERT INTO games (room, state, score, wave, duration_ms, finished_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![room, state, summary.score as i64, summary.wave as i64, summary.duration_ms as i64, finished_at as i64],
+        ).map_err(|err| err.to_string())?;
+        let game_id = tx.last_insert_rowid();
+        for player in summary.players.iter() {
+            tx.execute(
+                "INSERT INTO game_players (game_id, player_id, name, score, kills, accuracy, survived_ms) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    game_id,
+                    player.player_id,
+                    player.name,
+                    player.score as i64,
+                    player.kills as i64,
+                    player.accuracy as f64,
+                    player.survived_ms as i64,
+                ],
+            ).map_err(|err| err.to_string())?;
+        }
+        let events = serde_json::to_string(&summary.replay).map_err(|err| err.to_string())?;
+        tx.execute(
+            "INSERT INTO game_replays (game_id, seed, events) VALUES (?1, ?2, ?3)",
+            params![game_id, summary.seed as i64, events],
+        ).map_err(|err| err.to_string())?;
+        tx.commit().map_err(|err| err.to_string())
+    }
+
+    /// Average score over the player's most recent games, used as a matchmaking rating.
+    pub fn rating(&self, player_id: &str) -> Result<Option<f64>, String> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT AVG(score) FROM (
+                 SELECT p.score FROM game_players p JOIN games g ON g.id = p.game_id
+                 WHERE p.player_id = ?1 ORDER BY g.finished_at DESC LIMIT ?2
+             )",
+            params![player_id, RATING_GAMES],
+            |row| row.get::<_, Option<f64>>(0),
+        ).map_err(|err| err.to_string())
+    }
+
+    /// Best individual results within `scope`, highest score first.
+    pub fn top(&self, scope: Scope, limit: usize) -> Result<Vec<Entry>, String> {
+        let (filter, arg) = match scope {
+            Scope::Overall => ("1 = 1", None),
+            Scope::Today => ("date(g.finished_at, 'unixepoch') = date('now')", None),
+            Scope::Day(day) => ("date(g.finished_at, 'unixepoch') = ?2", Some(day)),
+            Scope::Room(room) => ("g.room = ?2", Some(room)),
+        };
+        let sql = format!(
+            "SELECT p.name, p.player_id, p.score, p.kills, p.accuracy, g.wave, g.room, g.duration_ms, g.finished_at
+             FROM game_players p JOIN games g ON g.id = p.game_id
+             WHERE {filter}
+             ORDER BY p.score DESC, g.finished_at ASC
+             LIMIT ?1"
+        );
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&sql).map_err(|err| err.to_string())?;
+        let limit = limit.clamp(1, MAX_LIMIT) as i64;
+        let map_row = |row: &rusqlite::Row| Ok(Entry {
+            name: row.get(0)?,
+            player_id: row.get(1)?,
+            score: row.get::<_, i64>(2)? as usize,
+            kills: row.get::<_, i64>(3)? as usize,
+            accuracy: row.get::<_, f64>(4)? as f32,
+            wave: row.get::<_, i64>(5)? as usize,
+            room: row.get(6)?,
+            duration_ms: row.get::<_, i64>(7)? as u64,
+            finished_at: row.get::<_, i64>(8)? as u64,
+        });
+        let rows = match arg {
+            Some(arg) => stmt.query_map(params![limit, arg], map_row),
+            None => stmt.query_map(params![limit], map_row),
+        };
+        rows.and_then(|rows| rows.collect())
+            .map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::player::Input;
+    use crate::game::snapshot::{PlayerSummary, ReplayChange, ReplayEvent};
+    use crate::game_session::GameStateType;
+
+    fn summary(player_id: Option<&str>, score: usize) -> GameSummary {
+        GameSummary {
+            state: GameStateType::LOSE,
+            seed: 1,
+            score,
+            wave: 1,
+            duration_ms: 1000,
+            players: vec![PlayerSummary {
+                id: 1,
+                player_id: player_id.map(str::to_string),
+                name: "Player 1".to_string(),
+                score,
+                kills: 0,
+                shots_fired: 0,
+                hits: 0,
+                accuracy: 0.0,
+                best_combo: 0,
+                survived_ms: 1000,
+                lives: 0,
+            }],
+            replay: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn anonymous_players_are_kept_out_of_ratings() {
+        let leaderboard = Leaderboard::open(":memory:").unwrap();
+        leaderboard.record("a", &summary(None, 10)).unwrap();
+        leaderboard.record("b", &summary(None, 30)).unwrap();
+        leaderboard.record("c", &summary(Some("p1"), 20)).unwrap();
+
+        let top = leaderboard.top(Scope::Overall, 10).unwrap();
+        assert_eq!(top.iter().map(|entry| entry.player_id.as_deref()).collect::<Vec<_>>(), vec![None, Some("p1"), None]);
+        assert_eq!(leaderboard.rating("1").unwrap(), None);
+        assert_eq!(leaderboard.rating("p1").unwrap(), Some(20.0));
+    }
+
+    #[test]
+    fn replays_are_stored_with_the_game() {
+        let leaderboard = Leaderboard::open(":memory:").unwrap();
+        let replay = vec![
+            ReplayEvent { tick: 1, change: ReplayChange::JOIN { slot: 0 } },
+            ReplayEvent { tick: 5, change: ReplayChange::INPUT { slot: 0, action: Input::FIRE } },
+        ];
+        let game = GameSummary { seed: u64::MAX, replay: replay.clone(), ..summary(None, 10) };
+        assert!(!serde_json::to_string(&game).unwrap().contains("replay"));
+        leaderboard.record("a", &game).unwrap();
+
+        let conn = leaderboard.conn.lock().unwrap();
+        let (seed, events): (i64, String) = conn.query_row("SELECT seed, events FROM game_replays", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!(seed as u64, u64::MAX);
+        assert_eq!(events, serde_json::to_string(&replay).unwrap());
+    }
+}
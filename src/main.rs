@@ -1,34 +1,67 @@
+#![allow(clippy::upper_case_acronyms)]
+use std::{io, sync::Arc};
 use actix::*;
 use actix_cors::Cors;
 use actix_web::{web, http, App, HttpServer};
 
+mod config;
 mod server;
 mod routes;
 mod session;
 mod room;
 mod game;
 mod game_session;
+mod protocol;
+mod leaderboard;
+mod auth;
+mod matchmaking;
+mod chat;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let server = server::ChatServer::new().start();
-    let server_addr = "127.0.0.1";
-    let server_port = 8089;
+    let config = config::Config::load()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let leaderboard = Arc::new(
+        leaderboard::Leaderboard::open(&config.leaderboard.path)
+            .map_err(io::Error::other)?
+    );
+    let filter = chat::WordFilter::load(&config.chat.filter_path)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let auth = auth::Auth::new(config.auth.clone());
+    let server = server::ChatServer::new(&config, leaderboard.clone(), Box::new(filter), Arc::new(game::clock::SystemClock::default())).start();
+    let server_addr = config.server.bind_address.clone();
+    let server_port = config.server.port;
+    let workers = config.server.workers;
+    let app_config = config.clone();
     let app = HttpServer::new(move || {
-        let cors = Cors::default()
-            .allowed_origin("http://localhost:3000")
-            .allowed_origin("http://localhost:8080")
-            .allowed_methods(vec!["GET", "POST"])
+        let cors = app_config.server.cors_origins.iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+            .allowed_methods(vec!["GET", "POST", "DELETE"])
             .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
             .allowed_header(http::header::CONTENT_TYPE)
             .max_age(3600);
         App::new()
             .app_data(web::Data::new(server.clone()))
+            .app_data(web::Data::new(app_config.clone()))
+            .app_data(web::Data::from(leaderboard.clone()))
+            .app_data(web::Data::new(auth.clone()))
             .wrap(cors)
             .route("/ws", web::get().to(routes::chat_server))
+            .route("/auth/token", web::post().to(routes::issue_token))
+            .route("/leaderboard", web::get().to(routes::leaderboard))
+            .route("/leaderboard/daily", web::get().to(routes::leaderboard_daily))
+            .route("/leaderboard/rooms/{room}", web::get().to(routes::leaderboard_room))
+            .service(
+                web::scope("/admin")
+                    .route("/rooms", web::get().to(routes::admin_list_rooms))
+                    .route("/rooms/{room}", web::get().to(routes::admin_get_room))
+                    .route("/rooms/{room}", web::delete().to(routes::admin_close_room))
+                    .route("/rooms/{room}/stop", web::post().to(routes::admin_stop_game))
+                    .route("/sessions/{id}", web::delete().to(routes::admin_kick_session))
+            )
     })
-    .workers(2)
-    .bind((server_addr, server_port))?
+    .workers(workers)
+    .bind((server_addr.as_str(), server_port))?
     .run();
     println!("Server running at http://{server_addr}:{server_port}/");
     app.await
@@ -0,0 +1,71 @@
+use std::time::{Duration, Instant};
+use crate::config::MatchmakingConfig;
+
+#[derive(Debug)]
+struct Ticket {
+    session_id: usize,
+    /// Players wanted in the match, the room capacity it will be created with.
+    capacity: usize,
+    /// Average recent score; sessions without history match anyone.
+    rating: Option<f64>,
+    since: Instant,
+}
+
+/// Sessions waiting for a match, oldest first.
+#[derive(Debug, Default)]
+pub struct Queue {
+    tickets: Vec<Ticket>,
+}
+
+impl Queue {
+    pub fn enqueue(&mut self, session_id: usize, capacity: usize, rating: Option<f64>) {
+        self.remove(session_id);
+        self.tickets.push(Ticket { session_id, capacity, rating, since: Instant::now() });
+    }
+
+    pub fn remove(&mut self, session_id: usize) -> bool {
+        let before = self.tickets.len();
+        self.tickets.retain(|ticket| ticket.session_id != session_id);
+        self.tickets.len() != before
+    }
+
+    /// Takes the first group that can fill a room, returning its capacity and session ids.
+    /// A ticket accepts others whose rating is within a window that widens the longer it waits.
+    pub fn next_match(&mut self, config: &MatchmakingConfig) -> Option<(usize, Vec<usize>)> {
+        for (idx, anchor) in self.tickets.iter().enumerate() {
+            let waited = anchor.since.elapsed().as_secs_f64();
+            let window = config.rating_window + config.rating_window_growth * waited;
+            let mut candidates: Vec<(f64, usize)> = self.tickets.iter()
+                .enumerate()
+                .filter(|(other, ticket)| *other != idx && ticket.capacity == anchor.capacity)
+                .filter_map(|(other, ticket)| {
+                    let distance = match (anchor.rating, ticket.rating) {
+                        (Some(a), Some(b)) => (a - b).abs(),
+                        _ => 0.0,
+                    };
+                    (distance <= window).then_some((distance, other))
+                })
+                .collect();
+            if candidates.len() + 1 < anchor.capacity {
+                continue;
+            }
+            candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let mut picked: Vec<usize> = candidates.iter().take(anchor.capacity - 1).map(|(_, other)| *other).collect();
+            picked.push(idx);
+            // Remove from the back so earlier indices stay valid, then restore queue order.
+            picked.sort_unstable_by(|a, b| b.cmp(a));
+            let capacity = anchor.capacity;
+            let mut sessions: Vec<usize> = picked.into_iter().map(|idx| self.tickets.remove(idx).session_id).collect();
+            sessions.reverse();
+            return Some((capacity, sessions));
+        }
+        None
+    }
+
+    /// Removes and returns the sessions that have waited longer than `timeout`.
+    pub fn expired(&mut self, timeout: Duration) -> Vec<usize> {
+        let (expired, waiting) = self.tickets.drain(..).partition(|ticket| ticket.since.elapsed() >= timeout);
+        self.tickets = waiting;
+        expired.into_iter().map(|ticket: Ticket| ticket.session_id).collect()
+    }
+}
@@ -0,0 +1,548 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::game::frame::Frame;
+use crate::game::player::Input;
+use crate::game::snapshot::{GameSummary, Snapshot};
+use crate::chat::ChatLine;
+use crate::room::{LobbyInfo, Role, RoomSettings};
+use crate::session::{ChatMessage, ChatType};
+
+pub const DELTA_V1: &str = "spacews.delta.v1";
+pub const BINARY_V1: &str = "spacews.binary.v1";
+pub const SUPPORTED_PROTOCOLS: [&str; 2] = [DELTA_V1, BINARY_V1];
+const KEYFRAME_INTERVAL: u64 = 50;
+
+/// Glyph codes used by the binary protocol; a cell is encoded as its index in this table.
+const GLYPHS: [&str; 7] = [" ", "A", "|", "*", "x", "+", "!"];
+
+const TAG_KEYFRAME: u8 = 0x01;
+const TAG_DELTA: u8 = 0x02;
+
+const OP_MOVEMENT: u8 = 0x01;
+const OP_KEYFRAME: u8 = 0x02;
+const OP_JOIN: u8 = 0x03;
+
+/// Protocol version: clients send it as `v` on every command and every server packet carries it.
+const PROTOCOL_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameProtocol {
+    LEGACY,
+    DELTA,
+    BINARY,
+}
+
+impl FrameProtocol {
+    /// Picks the first subprotocol offered in `Sec-WebSocket-Protocol` that we support,
+    /// falling back to the legacy full-grid JSON when the client offers none.
+    pub fn negotiate(offered: Option<&str>) -> FrameProtocol {
+        offered.unwrap_or("")
+            .split(',')
+            .find_map(|p| match p.trim() {
+                DELTA_V1 => Some(FrameProtocol::DELTA),
+                BINARY_V1 => Some(FrameProtocol::BINARY),
+                _ => None,
+            })
+            .unwrap_or(FrameProtocol::LEGACY)
+    }
+}
+
+pub enum Encoded {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum ServerPacket<'a> {
+    KEYFRAME {
+        v: u8,
+        seq: u64,
+        /// Seq of the receiving player's last applied INPUT, for client-side prediction.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ack: Option<u64>,
+        rows: usize,
+        cols: usize,
+        cells: &'a Frame,
+    },
+    DELTA {
+        v: u8,
+        seq: u64,
+        base: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ack: Option<u64>,
+        changes: Vec<(usize, usize, &'static str)>,
+    },
+    SNAPSHOT {
+        v: u8,
+        #[serde(flatten)]
+        snapshot: &'a Snapshot,
+    },
+    SUMMARY {
+        v: u8,
+        #[serde(flatten)]
+        summary: &'a GameSummary,
+    },
+    LOBBY {
+        v: u8,
+        #[serde(flatten)]
+        lobby: &'a LobbyInfo,
+    },
+    HISTORY {
+        v: u8,
+        channel: &'a str,
+        messages: Vec<&'a ChatLine>,
+    },
+    TEXT {
+        v: u8,
+        #[serde(flatten)]
+        line: &'a ChatLine,
+    },
+    TYPING {
+        v: u8,
+        channel: &'a str,
+        from: &'a str,
+        from_id: usize,
+    },
+    ROLE {
+        v: u8,
+        role: Role,
+    },
+    /// Players also get the token to RESUME their slot with after a dropped connection.
+    RESUME {
+        v: u8,
+        room: &'a str,
+        token: &'a str,
+    },
+    NOTICE {
+        v: u8,
+        message: &'a str,
+    },
+    PAUSE {
+        v: u8,
+        paused: bool,
+    },
+    COUNTDOWN {
+        v: u8,
+        secs: u64,
+    },
+    WAVE {
+        v: u8,
+        wave: usize,
+    },
+    /// Whether the session is waiting in the matchmaking queue.
+    MATCHMAKE {
+        v: u8,
+        queued: bool,
+    },
+    /// Matchmaking moved the session into `room`.
+    MATCHED {
+        v: u8,
+        room: &'a str,
+    },
+    ERROR {
+        v: u8,
+        code: ErrorCode,
+        /// Type of the command that failed, when it could be read.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        command: Option<&'a str>,
+        message: &'a str,
+    },
+}
+
+/// Commands clients send as JSON text, tagged by `type` and carrying the protocol version `v`,
+/// e.g. `{"type":"INPUT","v":1,"action":"FIRE"}`.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum ClientCommand {
+    JOIN {
+        room: String,
+        #[serde(flatten)]
+        settings: RoomSettings,
+    },
+    /// Authenticated clients may leave out the token and reclaim their slot by identity.
+    RESUME {
+        room: String,
+        #[serde(default)]
+        token: String,
+    },
+    /// `seq` is the client's own counter, echoed back once the input is applied: as `ack` on
+    /// delta and binary frames, otherwise in snapshots, which sending a `seq` switches on.
+    INPUT {
+        action: Input,
+        #[serde(default)]
+        seq: Option<u64>,
+    },
+    READY {
+        ready: bool,
+    },
+    SETTINGS {
+        #[serde(flatten)]
+        settings: RoomSettings,
+    },
+    PAUSE {
+        paused: bool,
+    },
+    /// Queue for a match of `capacity` players, two by default.
+    MATCHMAKE {
+        #[serde(default)]
+        capacity: Option<usize>,
+    },
+    DEQUEUE {},
+    /// Chat text for `channel`, or the sender's room channel when it is left out.
+    TEXT {
+        text: String,
+        #[serde(default)]
+        channel: Option<String>,
+    },
+    TYPING {
+        #[serde(default)]
+        channel: Option<String>,
+    },
+    SUBSCRIBE {
+        channel: String,
+    },
+    UNSUBSCRIBE {
+        channel: String,
+    },
+    KEYFRAME {},
+    SNAPSHOTS {
+        enabled: bool,
+    },
+}
+
+impl ClientCommand {
+    /// The command's `type`, echoed back in error replies.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientCommand::JOIN { .. } => "JOIN",
+            ClientCommand::RESUME { .. } => "RESUME",
+            ClientCommand::INPUT { .. } => "INPUT",
+            ClientCommand::READY { .. } => "READY",
+            ClientCommand::SETTINGS { .. } => "SETTINGS",
+            ClientCommand::PAUSE { .. } => "PAUSE",
+            ClientCommand::MATCHMAKE { .. } => "MATCHMAKE",
+            ClientCommand::DEQUEUE {} => "DEQUEUE",
+            ClientCommand::TEXT { .. } => "TEXT",
+            ClientCommand::TYPING { .. } => "TYPING",
+            ClientCommand::SUBSCRIBE { .. } => "SUBSCRIBE",
+            ClientCommand::UNSUBSCRIBE { .. } => "UNSUBSCRIBE",
+            ClientCommand::KEYFRAME {} => "KEYFRAME",
+            ClientCommand::SNAPSHOTS { .. } => "SNAPSHOTS",
+        }
+    }
+
+    /// Converts a pre-versioning `{"chat_type": ..., "value": ...}` message.
+    fn from_legacy(msg: ChatMessage) -> Result<ClientCommand, String> {
+        let settings = RoomSettings { capacity: msg.capacity, cols: msg.cols, rows: msg.rows, level: msg.level, seed: None };
+        let command = match msg.chat_type {
+            ChatType::JOIN => ClientCommand::JOIN { room: msg.value, settings },
+            ChatType::RESUME => ClientCommand::RESUME { room: msg.value, token: msg.token.unwrap_or_default() },
+            ChatType::MOVEMENT => {
+                let action = match msg.value.as_str() {
+                    "-1" => Input::LEFT,
+                    "1" => Input::RIGHT,
+                    "-" => Input::FIRE,
+                    other => return Err(format!("invalid movement {other:?}")),
+                };
+                ClientCommand::INPUT { action, seq: None }
+            },
+            ChatType::READY => ClientCommand::READY { ready: msg.value != "off" },
+            ChatType::SETTINGS => ClientCommand::SETTINGS { settings },
+            ChatType::PAUSE => ClientCommand::PAUSE { paused: msg.value != "off" },
+            ChatType::MATCHMAKE if msg.value == "off" => ClientCommand::DEQUEUE {},
+            ChatType::MATCHMAKE => ClientCommand::MATCHMAKE { capacity: msg.capacity },
+            ChatType::TEXT => ClientCommand::TEXT { text: msg.value, channel: msg.channel },
+            ChatType::TYPING => ClientCommand::TYPING { channel: msg.channel },
+            ChatType::SUBSCRIBE => ClientCommand::SUBSCRIBE { channel: msg.value },
+            ChatType::UNSUBSCRIBE => ClientCommand::UNSUBSCRIBE { channel: msg.value },
+            ChatType::KEYFRAME => ClientCommand::KEYFRAME {},
+            ChatType::SNAPSHOT => ClientCommand::SNAPSHOTS { enabled: msg.value != "off" },
+        };
+        Ok(command)
+    }
+}
+
+/// Why a command was refused, so clients can tell bad input from a rejected request.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    /// Not JSON, an unknown `type`, or a payload of the wrong shape.
+    MALFORMED,
+    /// `v` is missing or names a protocol version the server does not speak.
+    VERSION,
+    /// Well formed, but not allowed right now; the message says why.
+    REJECTED,
+}
+
+#[derive(Debug)]
+pub struct CommandError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl CommandError {
+    fn new(code: ErrorCode, message: impl ToString) -> CommandError {
+        CommandError { code, message: message.to_string() }
+    }
+}
+
+/// Parses a JSON text command. Messages with a `chat_type` are read as the legacy format.
+pub fn decode_command(text: &str) -> Result<ClientCommand, CommandError> {
+    let value: Value = serde_json::from_str(text).map_err(|err| CommandError::new(ErrorCode::MALFORMED, err))?;
+    if value.get("chat_type").is_some() {
+        let msg = serde_json::from_value::<ChatMessage>(value).map_err(|err| CommandError::new(ErrorCode::MALFORMED, err))?;
+        return ClientCommand::from_legacy(msg).map_err(|err| CommandError::new(ErrorCode::MALFORMED, err));
+    }
+    match value.get("v").and_then(Value::as_u64) {
+        Some(v) if v == PROTOCOL_VERSION as u64 => (),
+        Some(v) => return Err(CommandError::new(ErrorCode::VERSION, format!("unsupported protocol version {v}, expected {PROTOCOL_VERSION}"))),
+        None => return Err(CommandError::new(ErrorCode::VERSION, format!("missing protocol version \"v\", expected {PROTOCOL_VERSION}"))),
+    }
+    serde_json::from_value(value).map_err(|err| CommandError::new(ErrorCode::MALFORMED, err))
+}
+
+/// Error reply to a command; `command` names the command that failed when known.
+pub fn encode_error(code: ErrorCode, command: Option<&str>, message: &str) -> String {
+    serde_json::to_string(&ServerPacket::ERROR { v: PROTOCOL_VERSION, code, command, message }).unwrap()
+}
+
+/// Per-connection encoder that remembers the last frame sent so it can emit only changed cells.
+#[derive(Debug)]
+pub struct FrameEncoder {
+    pub protocol: FrameProtocol,
+    last_frame: Option<Frame>,
+    seq: u64,
+    since_keyframe: u64,
+}
+
+impl FrameEncoder {
+    pub fn new(protocol: FrameProtocol) -> FrameEncoder {
+        FrameEncoder {
+            protocol,
+            last_frame: None,
+            seq: 0,
+            since_keyframe: 0,
+        }
+    }
+
+    pub fn request_keyframe(&mut self) {
+        self.last_frame = None;
+    }
+
+    /// `ack` is the seq of the receiving player's last applied input. Only JSON keyframes and deltas
+    /// carry it; the legacy grid and the packed binary layout leave it to snapshots.
+    pub fn encode(&mut self, frame: &Frame, ack: Option<u64>) -> Encoded {
+        if self.protocol == FrameProtocol::LEGACY {
+            return Encoded::Text(serde_json::to_string(&Some(frame)).unwrap());
+        }
+
+        self.seq += 1;
+        let packet = match &self.last_frame {
+            Some(last) if self.since_keyframe < KEYFRAME_INTERVAL && same_size(last, frame) => {
+                self.since_keyframe += 1;
+                let changes = frame.iter().enumerate()
+                    .flat_map(|(y, row)| {
+                        row.iter().enumerate()
+                            .filter(move |(x, cell)| last[y][*x] != **cell)
+                            .map(move |(x, cell)| (y, x, *cell))
+                    })
+                    .collect();
+                ServerPacket::DELTA { v: PROTOCOL_VERSION, seq: self.seq, base: self.seq - 1, ack, changes }
+            },
+            _ => {
+                self.since_keyframe = 0;
+                ServerPacket::KEYFRAME {
+                    v: PROTOCOL_VERSION,
+                    seq: self.seq,
+                    ack,
+                    rows: frame.len(),
+                    cols: frame.first().map(|row| row.len()).unwrap_or(0),
+                    cells: frame,
+                }
+            },
+        };
+        let res = match self.protocol {
+            FrameProtocol::BINARY => Encoded::Binary(pack(&packet)),
+            _ => Encoded::Text(serde_json::to_string(&packet).unwrap()),
+        };
+        self.last_frame = Some(frame.clone());
+        res
+    }
+
+    /// Snapshots are self-contained and always sent whole as JSON text, regardless of the frame protocol.
+    pub fn encode_snapshot(&self, snapshot: &Snapshot) -> String {
+        serde_json::to_string(&ServerPacket::SNAPSHOT { v: PROTOCOL_VERSION, snapshot }).unwrap()
+    }
+}
+
+/// End-of-game results go out as JSON text to every session, whatever frame protocol it negotiated.
+pub fn encode_summary(summary: &GameSummary) -> String {
+    serde_json::to_string(&ServerPacket::SUMMARY { v: PROTOCOL_VERSION, summary }).unwrap()
+}
+
+/// Lobby state goes out as JSON text, like the summary.
+pub fn encode_lobby(lobby: &LobbyInfo) -> String {
+    serde_json::to_string(&ServerPacket::LOBBY { v: PROTOCOL_VERSION, lobby }).unwrap()
+}
+
+/// Recent messages of a channel, sent to a session when it joins it.
+pub fn encode_history<'a>(channel: &'a str, messages: Vec<&'a ChatLine>) -> String {
+    serde_json::to_string(&ServerPacket::HISTORY { v: PROTOCOL_VERSION, channel, messages }).unwrap()
+}
+
+pub fn encode_text(line: &ChatLine) -> String {
+    serde_json::to_string(&ServerPacket::TEXT { v: PROTOCOL_VERSION, line }).unwrap()
+}
+
+pub fn encode_typing(channel: &str, from: &str, from_id: usize) -> String {
+    serde_json::to_string(&ServerPacket::TYPING { v: PROTOCOL_VERSION, channel, from, from_id }).unwrap()
+}
+
+pub fn encode_role(role: Role) -> String {
+    serde_json::to_string(&ServerPacket::ROLE { v: PROTOCOL_VERSION, role }).unwrap()
+}
+
+pub fn encode_resume(room: &str, token: &str) -> String {
+    serde_json::to_string(&ServerPacket::RESUME { v: PROTOCOL_VERSION, room, token }).unwrap()
+}
+
+pub fn encode_notice(message: &str) -> String {
+    serde_json::to_string(&ServerPacket::NOTICE { v: PROTOCOL_VERSION, message }).unwrap()
+}
+
+pub fn encode_pause(paused: bool) -> String {
+    serde_json::to_string(&ServerPacket::PAUSE { v: PROTOCOL_VERSION, paused }).unwrap()
+}
+
+/// Whole seconds left before a lobby's game starts.
+pub fn encode_countdown(secs: u64) -> String {
+    serde_json::to_string(&ServerPacket::COUNTDOWN { v: PROTOCOL_VERSION, secs }).unwrap()
+}
+
+pub fn encode_wave(wave: usize) -> String {
+    serde_json::to_string(&ServerPacket::WAVE { v: PROTOCOL_VERSION, wave }).unwrap()
+}
+
+pub fn encode_matchmake(queued: bool) -> String {
+    serde_json::to_string(&ServerPacket::MATCHMAKE { v: PROTOCOL_VERSION, queued }).unwrap()
+}
+
+pub fn encode_matched(room: &str) -> String {
+    serde_json::to_string(&ServerPacket::MATCHED { v: PROTOCOL_VERSION, room }).unwrap()
+}
+
+fn same_size(a: &Frame, b: &Frame) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(ra, rb)| ra.len() == rb.len())
+}
+
+fn glyph_code(glyph: &str) -> u8 {
+    GLYPHS.iter().position(|g| *g == glyph).unwrap_or(0) as u8
+}
+
+/// Little-endian layout, where `ack` is 0 until one of the player's inputs has been applied:
+/// keyframe `[0x01][v u8][seq u32][ack u32][rows u16][cols u16][rows*cols glyph codes]`,
+/// delta `[0x02][v u8][seq u32][ack u32][count u16][count * (y u16, x u16, glyph u8)]`.
+fn pack(packet: &ServerPacket) -> Vec<u8> {
+    let mut buf = Vec::new();
+    match packet {
+        ServerPacket::KEYFRAME { v, seq, ack, rows, cols, cells } => {
+            buf.reserve(14 + rows * cols);
+            buf.push(TAG_KEYFRAME);
+            buf.push(*v);
+            buf.extend_from_slice(&(*seq as u32).to_le_bytes());
+            buf.extend_from_slice(&(ack.unwrap_or(0) as u32).to_le_bytes());
+            buf.extend_from_slice(&(*rows as u16).to_le_bytes());
+            buf.extend_from_slice(&(*cols as u16).to_le_bytes());
+            for row in cells.iter() {
+                buf.extend(row.iter().map(|cell| glyph_code(cell)));
+            }
+        },
+        ServerPacket::DELTA { v, seq, ack, changes, .. } => {
+            buf.reserve(12 + changes.len() * 5);
+            buf.push(TAG_DELTA);
+            buf.push(*v);
+            buf.extend_from_slice(&(*seq as u32).to_le_bytes());
+            buf.extend_from_slice(&(ack.unwrap_or(0) as u32).to_le_bytes());
+            buf.extend_from_slice(&(changes.len() as u16).to_le_bytes());
+            for (y, x, cell) in changes.iter() {
+                buf.extend_from_slice(&(*y as u16).to_le_bytes());
+                buf.extend_from_slice(&(*x as u16).to_le_bytes());
+                buf.push(glyph_code(cell));
+            }
+        },
+        // Everything else only ever goes out as JSON text.
+        _ => (),
+    }
+    buf
+}
+
+/// Decodes a binary client command into the equivalent JSON command:
+/// movement `[0x01][dir i8][seq u32]` (-1 left, 1 right, 0 shoot; seq 0 or left out for none),
+/// keyframe request `[0x02]`,
+/// join `[0x03][capacity u8, 0 for default][room name utf8]`.
+pub fn unpack_command(bytes: &[u8]) -> Result<ClientCommand, CommandError> {
+    let malformed = |message: String| CommandError::new(ErrorCode::MALFORMED, message);
+    match bytes.split_first() {
+        Some((&OP_MOVEMENT, [dir, seq @ ..])) => {
+            let action = match *dir as i8 {
+                -1 => Input::LEFT,
+                1 => Input::RIGHT,
+                0 => Input::FIRE,
+                other => return Err(malformed(format!("invalid movement {other}"))),
+            };
+            let seq = match *seq {
+                [] => None,
+                [a, b, c, d] => Some(u32::from_le_bytes([a, b, c, d])).filter(|seq| *seq != 0).map(u64::from),
+                _ => return Err(malformed("movement seq must be a u32".to_string())),
+            };
+            Ok(ClientCommand::INPUT { action, seq })
+        },
+        Some((&OP_KEYFRAME, [])) => Ok(ClientCommand::KEYFRAME {}),
+        Some((&OP_JOIN, [capacity, name @ ..])) => {
+            let room = std::str::from_utf8(name).map_err(|err| malformed(err.to_string()))?.to_string();
+            let capacity = (*capacity > 0).then_some(*capacity as usize);
+            Ok(ClientCommand::JOIN { room, settings: RoomSettings { capacity, ..RoomSettings::default() } })
+        },
+        Some((op, _)) => Err(malformed(format!("invalid binary command {op:#04x}"))),
+        None => Err(malformed("empty binary command".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads `(tag, seq, ack)` back out of a binary frame header.
+    fn header(bytes: &[u8]) -> (u8, u32, u32) {
+        assert_eq!(bytes[1], PROTOCOL_VERSION);
+        let seq = u32::from_le_bytes(bytes[2..6].try_into().unwrap());
+        let ack = u32::from_le_bytes(bytes[6..10].try_into().unwrap());
+        (bytes[0], seq, ack)
+    }
+
+    #[test]
+    fn binary_movement_carries_its_seq() {
+        let mut bytes = vec![OP_MOVEMENT, -1i8 as u8];
+        assert_eq!(unpack_command(&bytes).unwrap(), ClientCommand::INPUT { action: Input::LEFT, seq: None });
+        bytes.extend_from_slice(&70_000u32.to_le_bytes());
+        assert_eq!(unpack_command(&bytes).unwrap(), ClientCommand::INPUT { action: Input::LEFT, seq: Some(70_000) });
+        let unsequenced = [OP_MOVEMENT, 0, 0, 0, 0, 0];
+        assert_eq!(unpack_command(&unsequenced).unwrap(), ClientCommand::INPUT { action: Input::FIRE, seq: None });
+        assert!(unpack_command(&[OP_MOVEMENT, 1, 7]).is_err());
+    }
+
+    #[test]
+    fn binary_frames_carry_the_ack() {
+        let mut encoder = FrameEncoder::new(FrameProtocol::BINARY);
+        let mut frame = vec![vec![" "; 3]; 2];
+        let Encoded::Binary(keyframe) = encoder.encode(&frame, None) else { panic!("expected a binary frame") };
+        assert_eq!(header(&keyframe), (TAG_KEYFRAME, 1, 0));
+        assert_eq!(u16::from_le_bytes(keyframe[10..12].try_into().unwrap()), 2);
+        assert_eq!(keyframe.len(), 14 + 6);
+
+        frame[1][2] = "A";
+        let Encoded::Binary(delta) = encoder.encode(&frame, Some(70_000)) else { panic!("expected a binary frame") };
+        assert_eq!(header(&delta), (TAG_DELTA, 2, 70_000));
+        assert_eq!(&delta[10..], &[1, 0, 1, 0, 2, 0, glyph_code("A")]);
+    }
+}
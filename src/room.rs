@@ -1,54 +1,199 @@
-use std::sync::{Arc, Mutex};
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex}, time::Duration};
 use actix::Addr;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use tokio::task::{self, JoinHandle};
-use tokio::time::{self, Duration, Instant};
-use crate::game_session::GameSession;
+use tokio::time;
+use crate::auth::Identity;
+use crate::config::GameConfig;
+use crate::game_session::{GameSession, GameStateType};
 use crate::server::{self};
-use crate::game::player::Player;
+use crate::game::clock::Clock;
+use crate::game::common::Board;
+use crate::game::player::{Input, Player};
+use crate::game::snapshot::ReplayChange;
+
+pub const DEFAULT_ROOM_CAPACITY: usize = 2;
+pub const MAX_ROOM_CAPACITY: usize = 8;
+
+/// Where a room is in its life: created with nobody in it (or everyone has left the lobby), gathering
+/// players in the lobby, playing, showing results after the game ended, and closed once evicted.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum RoomPhase {
+    CREATED,
+    LOBBY,
+    PLAYING,
+    FINISHED,
+    CLOSED,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    PLAYER,
+    SPECTATOR,
+}
+
+/// Options a client may pass with the JOIN that creates a room; ignored when joining an existing room.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct RoomSettings {
+    pub capacity: Option<usize>,
+    pub cols: Option<usize>,
+    pub rows: Option<usize>,
+    pub level: Option<String>,
+    /// Seed for the game's randomness; picked at random unless given here or in `game.seed`.
+    pub seed: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PlayerInfo {
+    pub slot: usize,
+    pub session_id: usize,
+    pub name: String,
+    pub lives: u8,
+    pub score: usize,
+}
+
+/// What the admin API reports about a room.
+#[derive(Serialize, Debug)]
+pub struct RoomInfo {
+    pub name: String,
+    pub phase: RoomPhase,
+    pub capacity: usize,
+    pub cols: usize,
+    pub rows: usize,
+    pub state: GameStateType,
+    pub running: bool,
+    pub score: usize,
+    pub tick: u64,
+    pub wave: usize,
+    pub seed: u64,
+    pub players: Vec<PlayerInfo>,
+    pub spectators: Vec<usize>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LobbyPlayer {
+    pub slot: usize,
+    pub session_id: usize,
+    pub name: String,
+    pub ready: bool,
+}
+
+/// Lobby state sent to members whenever someone joins, leaves, readies up or the host changes settings.
+#[derive(Serialize, Debug)]
+pub struct LobbyInfo {
+    pub host: Option<usize>,
+    pub capacity: usize,
+    pub cols: usize,
+    pub rows: usize,
+    pub level: Option<String>,
+    pub seed: u64,
+    pub counting_down: bool,
+    pub players: Vec<LobbyPlayer>,
+    pub spectators: Vec<usize>,
+}
 
 #[derive(Debug)]
 pub struct Room{
     pub name: String,
-    pub player1: Option<Arc<Mutex<Player>>>,
-    pub player2: Option<Arc<Mutex<Player>>>,
-    pub player1_session_id: usize,
-    pub player2_session_id: usize,
+    pub capacity: usize,
+    pub players: Vec<Option<Arc<Mutex<Player>>>>,
+    pub spectators: Vec<usize>,
+    /// Identities of authenticated members, kept so a promoted spectator plays under its own name.
+    identities: HashMap<usize, Identity>,
+    /// Player allowed to change the room settings in the lobby; the first player seated.
+    host: Option<usize>,
+    /// Players in the lobby who are ready to start.
+    ready: HashSet<usize>,
+    /// Set by a player's PAUSE; cleared once every active player has voted to resume.
+    manual_pause: bool,
+    resume_votes: HashSet<usize>,
     pub ticker_handle: Option<JoinHandle<()>>,
-    pub addr: Addr<crate::server::ChatServer>,
     pub game_session: Arc<Mutex<GameSession>>,
+    pub config: GameConfig,
+    /// Time source the game loop reads to decide how many ticks are due.
+    clock: Arc<dyn Clock>,
+    /// Where the game loop sends each tick's update.
+    server_addr: Addr<server::ChatServer>,
+    closed: bool,
+    /// Since when the room has had nobody connected or has sat finished; evicted once this passes the idle timeout.
+    pub idle_since: Option<std::time::Instant>,
 }
 
 impl Room{
-    pub fn new(name: String, server_addr: Addr<server::ChatServer>) -> Room {
-        let room = Self {
+    pub fn new(name: String, settings: &RoomSettings, config: GameConfig, clock: Arc<dyn Clock>, server_addr: Addr<server::ChatServer>) -> Result<Room, String> {
+        let (capacity, config, level) = Room::resolve_settings(settings, config)?;
+        let seed = settings.seed.or(config.seed).unwrap_or_else(|| rand::thread_rng().gen());
+        let game_session = Arc::new(Mutex::new(GameSession::new(name.clone(), capacity, config.clone(), seed)));
+        game_session.lock().unwrap().level = level;
+        Ok(Self {
             name:  name.clone(),
-            player1: None,
-            player2: None,
-            player1_session_id: 0,
-            player2_session_id: 0,
+            capacity,
+            players: vec![None; capacity],
+            spectators: Vec::new(),
+            identities: HashMap::new(),
+            host: None,
+            ready: HashSet::new(),
+            manual_pause: false,
+            resume_votes: HashSet::new(),
             ticker_handle: None,
-            addr: server_addr.clone(),
-            game_session: Arc::new(Mutex::new(GameSession::new(name.clone(), server_addr.clone())))
+            game_session,
+            config,
+            clock,
+            server_addr,
+            closed: false,
+            idle_since: None,
+        })
+    }
+
+    /// Checks the requested capacity, board and level against `config` and returns
+    /// the capacity, the config with the room's board, and the chosen level index.
+    fn resolve_settings(settings: &RoomSettings, mut config: GameConfig) -> Result<(usize, GameConfig, Option<usize>), String> {
+        let capacity = settings.capacity.unwrap_or(DEFAULT_ROOM_CAPACITY).clamp(1, MAX_ROOM_CAPACITY);
+        let board = Board {
+            cols: settings.cols.unwrap_or(config.board.cols),
+            rows: settings.rows.unwrap_or(config.board.rows),
+        };
+        board.validate()?;
+        let level = match &settings.level {
+            Some(name) => {
+                let idx = config.levels.iter().position(|level| &level.name == name)
+                    .ok_or_else(|| format!("unknown level {name:?}"))?;
+                config.levels[idx].fits(board)?;
+                Some(idx)
+            },
+            None => {
+                for level in config.levels.iter() {
+                    level.fits(board)?;
+                }
+                None
+            },
         };
-        room
+        config.board = board;
+        Ok((capacity, config, level))
     }
-    
+
+    /// Wakes up every tick and lets the game catch up with the room's clock in fixed-length steps.
+    /// The interval only decides when to look at the clock; the ticks simulated depend on the clock alone.
     pub fn run_game_session_update_loop(&mut self){
-        let invaders = crate::game::invaders::Invaders::new();
         let game_sesion_loop  = self.game_session.clone();
-        self.game_session.lock().unwrap().invaders = Some(Arc::new(Mutex::new(invaders)));
+        let clock = self.clock.clone();
+        let server_addr = self.server_addr.clone();
+        let tick = self.config.tick();
+        game_sesion_loop.lock().unwrap().restart_clock();
         let repeating_task = task::spawn(async move {
-            let mut interval = time::interval(Duration::from_millis(100));
-            let mut instant = Instant::now();
+            let mut interval = time::interval(tick);
             loop {
-                let delta = instant.elapsed();
-                instant = Instant::now();
-                game_sesion_loop.lock().unwrap().update_frame(delta);
+                let updates = game_sesion_loop.lock().unwrap().advance(clock.now());
+                for update in updates {
+                    server_addr.do_send(update);
+                }
                 interval.tick().await;
             }
         });
         self.ticker_handle = Some(repeating_task);
-    }    
+    }
 
     pub fn stop_update_loop(&mut self){
         if let Some(ticker_handle) = &self.ticker_handle {
@@ -57,116 +202,573 @@ impl Room{
         }
     }
 
-    pub fn disconnect_player(&mut self, player_id: usize){
-        if let Some(p1) = &self.player1 {
-            if p1.lock().unwrap().id == player_id {
-                println!("Player 1 {} disconnected from  {} ", player_id, &self.name);
-                self.player1.take();
-                let mut gs = self.game_session.lock().unwrap();
-                gs.player1.take();
-                gs.player1_sessionid = 0;
+    /// Force-stops the game; members stay in the room until it is closed.
+    pub fn stop_game(&mut self){
+        self.stop_update_loop();
+        self.game_session.lock().unwrap().stop();
+        println!("[INFO] Room [{}] game stopped by admin", self.name);
+    }
+
+    pub fn phase(&self) -> RoomPhase {
+        if self.closed {
+            return RoomPhase::CLOSED;
+        }
+        match *self.game_session.lock().unwrap().state.read().unwrap() {
+            GameStateType::IDLE if self.member_ids().is_empty() => RoomPhase::CREATED,
+            GameStateType::IDLE => RoomPhase::LOBBY,
+            GameStateType::START | GameStateType::PAUSE => RoomPhase::PLAYING,
+            GameStateType::STOP | GameStateType::WIN | GameStateType::LOSE => RoomPhase::FINISHED,
+        }
+    }
+
+    /// Stops the game for good; the caller removes the room from the server afterwards.
+    pub fn close(&mut self) {
+        self.stop_update_loop();
+        self.closed = true;
+    }
+
+    /// The settings this room was created with, for rebuilding it after its game has finished.
+    pub fn settings(&self) -> RoomSettings {
+        RoomSettings {
+            capacity: Some(self.capacity),
+            cols: Some(self.config.board.cols),
+            rows: Some(self.config.board.rows),
+            level: self.game_session.lock().unwrap().level.map(|idx| self.config.levels[idx].name.clone()),
+            seed: Some(self.game_session.lock().unwrap().seed),
+        }
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        let phase = self.phase();
+        let game_session = self.game_session.lock().unwrap();
+        let players = self.players.iter()
+            .enumerate()
+            .filter_map(|(slot, player)| player.as_ref().map(|player| (slot, player)))
+            .map(|(slot, player)| {
+                let player = player.lock().unwrap();
+                PlayerInfo {
+                    slot,
+                    session_id: player.id,
+                    name: player.name.clone(),
+                    lives: player.lives,
+                    score: player.stats.score,
+                }
+            })
+            .collect();
+        let state = game_session.state.read().unwrap().clone();
+        RoomInfo {
+            name: self.name.clone(),
+            phase,
+            capacity: self.capacity,
+            cols: self.config.board.cols,
+            rows: self.config.board.rows,
+            state,
+            running: self.ticker_handle.is_some(),
+            score: game_session.score,
+            tick: game_session.tick,
+            wave: game_session.wave,
+            seed: game_session.seed,
+            players,
+            spectators: self.spectators.clone(),
+        }
+    }
+
+    pub fn slot_of(&self, session_id: usize) -> Option<usize> {
+        self.players.iter().position(|slot| match slot {
+            Some(player) => player.lock().unwrap().id == session_id,
+            None => false,
+        })
+    }
+
+    pub fn resume_token(&self, session_id: usize) -> Option<String> {
+        let slot = self.slot_of(session_id)?;
+        self.players[slot].as_ref().map(|player| player.lock().unwrap().resume_token.clone())
+    }
+
+    /// Keeps a dropped player's ship in its slot so the player can resume. Returns false if it was already held.
+    pub fn hold_player(&mut self, session_id: usize) -> bool {
+        let Some(slot) = self.slot_of(session_id) else {
+            return false;
+        };
+        {
+            let mut player = self.players[slot].as_ref().unwrap().lock().unwrap();
+            if player.disconnected.is_some() {
+                return false;
+            }
+            player.disconnected = Some(std::time::Instant::now());
+        }
+        println!("Player {} {} dropped from room {}, holding slot", slot + 1, session_id, &self.name);
+        self.game_session.lock().unwrap().record(ReplayChange::HOLD { slot });
+        true
+    }
+
+    /// Session ids of held players whose grace period has run out.
+    pub fn expired_holds(&self, grace: Duration) -> Vec<usize> {
+        self.players.iter()
+            .flatten()
+            .filter_map(|player| {
+                let player = player.lock().unwrap();
+                match player.disconnected {
+                    Some(since) if since.elapsed() >= grace => Some(player.id),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    pub fn held_count(&self) -> usize {
+        self.players.iter()
+            .flatten()
+            .filter(|player| player.lock().unwrap().disconnected.is_some())
+            .count()
+    }
+
+    /// Gives a slot back to `session_id`, keeping ship position, lives and score. Authenticated sessions
+    /// reclaim the slot seated under their player id, token or not; a token for an authenticated
+    /// player's slot only works for that same player.
+    pub fn resume(&mut self, token: &str, session_id: usize, identity: Option<&Identity>) -> Result<(), String> {
+        let player_id = identity.map(|identity| identity.player_id.as_str());
+        let find = |matches: &dyn Fn(&Player) -> bool| self.players.iter()
+            .position(|player| player.as_ref().is_some_and(|player| matches(&player.lock().unwrap())));
+        let by_token = find(&|player| !token.is_empty() && player.resume_token == token);
+        let by_identity = find(&|player| player_id.is_some() && player.player_id.as_deref() == player_id);
+        let slot = by_token.or(by_identity).ok_or_else(|| "no slot to resume in this room".to_string())?;
+        let mut player = self.players[slot].as_ref().unwrap().lock().unwrap();
+        if player.player_id.is_some() && player.player_id.as_deref() != player_id {
+            return Err("that slot belongs to another player".to_string());
+        }
+        let previous = player.id;
+        let identity = identity.cloned().or_else(|| self.identities.get(&previous).cloned());
+        self.identities.remove(&previous);
+        if let Some(identity) = identity {
+            self.identities.insert(session_id, identity);
+        }
+        self.resume_votes.remove(&previous);
+        if self.ready.remove(&previous) {
+            self.ready.insert(session_id);
+        }
+        if self.host == Some(previous) {
+            self.host = Some(session_id);
+        }
+        // A reconnecting client may have rejoined the running game as a spectator before resuming.
+        self.spectators.retain(|id| *id != session_id);
+        player.resume(session_id);
+        drop(player);
+        self.game_session.lock().unwrap().record(ReplayChange::RESUME { slot });
+        println!("Player {} {} resumed in room {} as {}", slot + 1, previous, &self.name, session_id);
+        Ok(())
+    }
+
+    /// Players still in the game and connected; these are the ones who vote to resume.
+    fn active_players(&self) -> Vec<usize> {
+        self.players.iter()
+            .flatten()
+            .filter_map(|player| {
+                let player = player.lock().unwrap();
+                (player.disconnected.is_none() && !player.is_out()).then_some(player.id)
+            })
+            .collect()
+    }
+
+    pub fn pause(&mut self, session_id: usize) -> Result<Option<bool>, String> {
+        if self.slot_of(session_id).is_none() {
+            return Err("only players can pause".to_string());
+        }
+        if self.game_session.lock().unwrap().is_finished() {
+            return Err("the game is over".to_string());
+        }
+        if self.in_lobby() {
+            return Err("the game has not started".to_string());
+        }
+        self.manual_pause = true;
+        self.resume_votes.clear();
+        println!("[INFO] Room [{}] paused by {}", self.name, session_id);
+        Ok(self.sync_pause())
+    }
+
+    /// Records the player's vote to resume a manual pause; the pause lifts once every active player agrees.
+    pub fn vote_resume(&mut self, session_id: usize) -> Result<Option<bool>, String> {
+        if self.slot_of(session_id).is_none() {
+            return Err("only players can resume".to_string());
+        }
+        if !self.manual_pause {
+            return Err("the game is not paused".to_string());
+        }
+        self.resume_votes.insert(session_id);
+        Ok(self.sync_pause())
+    }
+
+    /// Votes cast and votes needed to lift the manual pause.
+    pub fn resume_votes(&self) -> (usize, usize) {
+        let active = self.active_players();
+        (active.iter().filter(|id| self.resume_votes.contains(id)).count(), active.len())
+    }
+
+    /// Pauses while a manual pause stands or a dropped player's slot is held, and resumes otherwise.
+    /// Returns the new pause state when it changed.
+    pub fn sync_pause(&mut self) -> Option<bool> {
+        if self.manual_pause {
+            let active = self.active_players();
+            if !active.is_empty() && active.iter().all(|id| self.resume_votes.contains(id)) {
+                println!("[INFO] Room [{}] all players agreed to resume", self.name);
+                self.manual_pause = false;
+                self.resume_votes.clear();
+            }
+        }
+        let paused = self.manual_pause || self.held_count() > 0;
+        let mut game_session = self.game_session.lock().unwrap();
+        if !game_session.is_playing() || game_session.is_paused() == paused {
+            return None;
+        }
+        game_session.set_paused(paused);
+        Some(paused)
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.players.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    /// Session ids of everyone watching the room, players first.
+    pub fn member_ids(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self.players.iter()
+            .flatten()
+            .map(|player| player.lock().unwrap().id)
+            .collect();
+        ids.extend(self.spectators.iter());
+        ids
+    }
+
+    pub fn is_host(&self, session_id: usize) -> bool {
+        self.host == Some(session_id)
+    }
+
+    pub fn is_spectator(&self, session_id: usize) -> bool {
+        self.spectators.contains(&session_id)
+    }
+
+    pub fn disconnect_spectator(&mut self, session_id: usize){
+        if let Some(idx) = self.spectators.iter().position(|id| *id == session_id) {
+            println!("Spectator {} left room {} ", session_id, &self.name);
+            self.spectators.remove(idx);
+            self.identities.remove(&session_id);
+        }
+    }
+
+    /// Frees the player's slot and hands it to the longest-waiting spectator, if any.
+    /// Returns the session id of the promoted spectator.
+    pub fn disconnect_player(&mut self, player_id: usize) -> Option<usize> {
+        let mut promoted = None;
+        if let Some(slot) = self.slot_of(player_id) {
+            println!("Player {} {} disconnected from room {} ", slot + 1, player_id, &self.name);
+            self.players[slot].take();
+            self.identities.remove(&player_id);
+            self.resume_votes.remove(&player_id);
+            self.ready.remove(&player_id);
+            self.game_session.lock().unwrap().unseat(slot);
+            if !self.spectators.is_empty() {
+                let spectator = self.spectators.remove(0);
+                self.seat_player(slot, spectator);
+                println!("Spectator {} promoted to player {} in room {} ", spectator, slot + 1, &self.name);
+                promoted = Some(spectator);
             }
-        } 
-        if  let Some(p2) = &self.player2 {
-            if p2.lock().unwrap().id == player_id {
-                println!("Player 2 {} disconnected from room {} ", player_id, &self.name);
-                self.player2.take();
-                let mut gs = self.game_session.lock().unwrap();
-                gs.player2.take();
-                gs.player2_sessionid = 0;
+            if self.host == Some(player_id) {
+                self.host = self.players.iter().flatten().map(|player| player.lock().unwrap().id).next();
+            }
+            if self.counting_down() {
+                self.cancel_countdown();
             }
         }
 
-        if self.player1.is_none() && self.player2.is_none() {
-            println!("Both player disconnected from room {} , stopping game loop", self.name);
+        if self.player_count() == 0 {
+            println!("All players disconnected from room {} , stopping game loop", self.name);
             self.stop_update_loop();
+            let mut game_session = self.game_session.lock().unwrap();
+            if game_session.is_playing() {
+                game_session.stop();
+            }
+        }
+        promoted
+    }
+
+    fn seat_player(&mut self, slot: usize, session_id: usize){
+        let mut player = Player::new(session_id, slot, self.capacity, &self.config);
+        player.room_id = Some(self.name.clone());
+        if let Some(identity) = self.identities.get(&session_id) {
+            player.player_id = Some(identity.player_id.clone());
+            player.name = identity.name.clone();
+        }
+        let player_arc = Arc::new(Mutex::new(player));
+        self.players[slot] = Some(player_arc.clone());
+        if let Ok(mut gs) = self.game_session.lock() {
+            gs.seat(slot, player_arc);
+        }
+    }
+
+    /// Seats the session in the first free slot, or adds it to the spectators when the room is full.
+    pub fn join(&mut self, session_id: usize, identity: Option<Identity>) -> Role {
+        if let Some(identity) = identity {
+            self.identities.insert(session_id, identity);
         }
+        if self.slot_of(session_id).is_some() {
+            println!("Player {} already in room {} ", session_id, &self.name);
+            return Role::PLAYER;
+        }
+        if self.is_spectator(session_id) {
+            return Role::SPECTATOR;
+        }
+        let free_slot = self.players.iter().position(|slot| slot.is_none());
+        match free_slot {
+            Some(slot) if self.in_lobby() && !self.counting_down() => {
+                self.seat_player(slot, session_id);
+                self.host.get_or_insert(session_id);
+                println!("Player {} {} joined room {} ", slot + 1, session_id, &self.name);
+                Role::PLAYER
+            },
+            _ => {
+                self.spectators.push(session_id);
+                println!("Room {} full or already playing, {} joined as spectator", self.name, session_id);
+                Role::SPECTATOR
+            },
+        }
+    }
+
+    /// True until the countdown finishes and the first wave starts.
+    pub fn in_lobby(&self) -> bool {
+        matches!(*self.game_session.lock().unwrap().state.read().unwrap(), GameStateType::IDLE)
+    }
+
+    pub fn counting_down(&self) -> bool {
+        self.game_session.lock().unwrap().counting_down()
     }
 
-    pub fn join2(&mut self, player: Arc<Mutex<Player>>){
-        if self.player1.is_none() {
-            self.player1 = Some(player.clone());
-            self.game_session.lock().unwrap().player1.replace(player.clone());
-            self.game_session.lock().unwrap().player1_sessionid = player.lock().unwrap().id;
-            let mut player = player.lock().unwrap();
-            player.room_id = Some(self.name.clone());
-            println!("Player 1 {} joined room {} ", player.id, &self.name);
-        } else if self.player2.is_none() {
-            player.lock().unwrap().move_up(); // shift player 2 up
-            self.player2 = Some(player.clone());
-            self.game_session.lock().unwrap().player2.replace(player.clone());
-            self.game_session.lock().unwrap().player2_sessionid = player.lock().unwrap().id;
-            let mut player = player.lock().unwrap();
-            player.room_id = Some(self.name.clone());            
-            println!("Player 2 {} joined room {} ", player.id, &self.name);
+    /// Marks a seated player ready or not. Once every seated player is ready the countdown starts;
+    /// anyone dropping their ready flag during the countdown cancels it.
+    pub fn set_ready(&mut self, session_id: usize, ready: bool) -> Result<(), String> {
+        if self.slot_of(session_id).is_none() {
+            return Err("only players can ready up".to_string());
+        }
+        if !self.in_lobby() {
+            return Err("the game has already started".to_string());
+        }
+        if ready {
+            self.ready.insert(session_id);
         } else {
-            println!("Unable to join room {} , already full", self.name);
+            self.ready.remove(&session_id);
         }
-        if self.ticker_handle.is_none() {
-            self.run_game_session_update_loop()
+        let all_ready = self.players.iter()
+            .flatten()
+            .all(|player| self.ready.contains(&player.lock().unwrap().id));
+        if all_ready && !self.counting_down() {
+            println!("[INFO] Room [{}] everyone is ready, starting countdown", self.name);
+            self.game_session.lock().unwrap().start_countdown();
+            self.run_game_session_update_loop();
+        } else if !all_ready && self.counting_down() {
+            self.cancel_countdown();
         }
+        Ok(())
+    }
+
+    fn cancel_countdown(&mut self) {
+        println!("[INFO] Room [{}] countdown cancelled", self.name);
+        self.stop_update_loop();
+        self.game_session.lock().unwrap().cancel_countdown();
     }
 
-    pub fn join(&mut self, session_id: usize){
-        if self.player1.is_none() {
-            let mut player = Player::new(session_id);
-            player.room_id = Some(self.name.clone());
-            let player_arc = Arc::new(Mutex::new(player)); 
-            self.player1 = Some(player_arc.clone());
-            self.player1_session_id = session_id;
-            if let Ok(mut gs) = self.game_session.lock() {
-                gs.player1.replace(player_arc.clone());
-                gs.player1_sessionid = self.player1_session_id;
+    /// Lets the host change capacity, board or level before the countdown. Settings left out stay as they are;
+    /// an empty level goes back to cycling through every level. Returns the spectators seated by a larger capacity.
+    pub fn update_settings(&mut self, session_id: usize, settings: &RoomSettings) -> Result<Vec<usize>, String> {
+        if self.host != Some(session_id) {
+            return Err("only the host can change room settings".to_string());
+        }
+        if !self.in_lobby() || self.counting_down() {
+            return Err("settings can only be changed in the lobby".to_string());
+        }
+        let current_level = self.game_session.lock().unwrap().level.map(|idx| self.config.levels[idx].name.clone());
+        let merged = RoomSettings {
+            capacity: settings.capacity.or(Some(self.capacity)),
+            cols: settings.cols.or(Some(self.config.board.cols)),
+            rows: settings.rows.or(Some(self.config.board.rows)),
+            level: match &settings.level {
+                Some(level) if level.is_empty() => None,
+                Some(level) => Some(level.clone()),
+                None => current_level,
+            },
+            seed: settings.seed.or(Some(self.game_session.lock().unwrap().seed)),
+        };
+        let (capacity, config, level) = Room::resolve_settings(&merged, self.config.clone())?;
+        // Seated players are rebuilt for the new board but keep the resume tokens they were already sent.
+        let seated: Vec<(usize, String)> = self.players.iter()
+            .flatten()
+            .map(|player| {
+                let player = player.lock().unwrap();
+                (player.id, player.resume_token.clone())
+            })
+            .collect();
+        if capacity < seated.len() {
+            return Err(format!("{} players are already seated", seated.len()));
+        }
+
+        let seed = merged.seed.unwrap_or_default();
+        let game_session = GameSession::new(self.name.clone(), capacity, config.clone(), seed);
+        self.game_session = Arc::new(Mutex::new(game_session));
+        self.game_session.lock().unwrap().level = level;
+        self.capacity = capacity;
+        self.config = config;
+        self.players = vec![None; capacity];
+        self.ready.clear();
+        for (slot, (session_id, token)) in seated.into_iter().enumerate() {
+            self.seat_player(slot, session_id);
+            if let Some(player) = &self.players[slot] {
+                player.lock().unwrap().resume_token = token;
             }
-            println!("Player 1 {} joined room {} ", session_id, &self.name);
-        } else if self.player2.is_none() {
-            let mut player = Player::new(session_id);
-            player.y = player.y - 1;
-            player.room_id = Some(self.name.clone());
-            let player_arc = Arc::new(Mutex::new(player)); 
-            self.player2 = Some(player_arc.clone());
-            self.player2_session_id = session_id;
-            if let Ok(mut gs) = self.game_session.lock() {
-                gs.player2.replace(player_arc.clone());
-                gs.player2_sessionid = self.player2_session_id;
+        }
+        let mut promoted = Vec::new();
+        while let Some(slot) = self.players.iter().position(|slot| slot.is_none()) {
+            if self.spectators.is_empty() {
+                break;
             }
-            println!("Player 2 {} joined room {} ", session_id, &self.name);
-         }
-        if self.ticker_handle.is_none() {
-            self.run_game_session_update_loop();
+            let spectator = self.spectators.remove(0);
+            self.seat_player(slot, spectator);
+            promoted.push(spectator);
         }
+        println!("[INFO] Room [{}] settings changed to {} players on {}x{}", self.name, capacity, self.config.board.cols, self.config.board.rows);
+        Ok(promoted)
     }
 
-    pub fn handle_player_input(&mut self, session_id: &usize, command: &str){
-        if *session_id == self.player1_session_id {
-            match &self.player1 {
-                Some(player) => {
-                    match player.lock(){
-                        Ok(mut p1) => {
-                            p1.handle_movement(command);
-                            ()
-                        },
-                        Err(_) => println!("[ERROR] handle_player_input: player 1 failed to get mutex {}", session_id),
-                    }
-                },
-                None => println!("[ERROR] handle_player_input: player 1 not found {}", session_id),
-            }
+    pub fn lobby(&self) -> LobbyInfo {
+        let game_session = self.game_session.lock().unwrap();
+        LobbyInfo {
+            host: self.host,
+            capacity: self.capacity,
+            cols: self.config.board.cols,
+            rows: self.config.board.rows,
+            level: game_session.level.map(|idx| self.config.levels[idx].name.clone()),
+            seed: game_session.seed,
+            counting_down: game_session.counting_down(),
+            players: self.players.iter()
+                .enumerate()
+                .filter_map(|(slot, player)| player.as_ref().map(|player| (slot, player)))
+                .map(|(slot, player)| {
+                    let player = player.lock().unwrap();
+                    LobbyPlayer { slot, session_id: player.id, name: player.name.clone(), ready: self.ready.contains(&player.id) }
+                })
+                .collect(),
+            spectators: self.spectators.clone(),
+        }
+    }
 
-        } else if *session_id == self.player2_session_id {
-            match &self.player2 {
-                Some(player) => {
+    /// Queues the input for the player's next tick; `seq` is echoed back in snapshots once applied.
+    pub fn handle_player_input(&mut self, session_id: &usize, input: Input, seq: Option<u64>) -> Result<(), String> {
+        match self.slot_of(*session_id) {
+            Some(slot) => {
+                if let Some(player) = &self.players[slot] {
+                    let gs = self.game_session.lock().unwrap();
+                    if !gs.is_playing() {
+                        return Err("the game has not started".to_string());
+                    }
+                    if gs.is_paused() {
+                        return Err("the game is paused".to_string());
+                    }
+                    drop(gs);
                     match player.lock(){
-                        Ok(mut p2) => {
-                            p2.handle_movement(command);
-                            ()
+                        Ok(mut p) => {
+                            if p.is_out() {
+                                return Err("no lives left, spectating until the game ends".to_string());
+                            }
+                            p.queue_input(input, seq)?;
                         },
-                        Err(_) => println!("[ERROR] handle_player_input: player 2 failed to get mutex {}", session_id),
+                        Err(_) => println!("[ERROR] handle_player_input: player {} failed to get mutex {}", slot + 1, session_id),
                     }
-                },
-                None => println!("[ERROR] handle_player_input: player 2 not found {}", session_id),
-            }
+                }
+                Ok(())
+            },
+            None if self.is_spectator(*session_id) => Err("spectators cannot move".to_string()),
+            None => {
+                println!("[ERROR] handle_player_input: player not found {}", session_id);
+                Err("not a player in this room".to_string())
+            },
         }
+    }
+
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::Actor;
+    use crate::chat::WordFilter;
+    use crate::config::Config;
+    use crate::game::clock::SystemClock;
+    use crate::leaderboard::Leaderboard;
+
+    fn new_room(capacity: usize) -> Room {
+        let config = Config::default();
+        let leaderboard = Arc::new(Leaderboard::open(":memory:").unwrap());
+        let server = server::ChatServer::new(&config, leaderboard, Box::new(WordFilter::default()), Arc::new(SystemClock::default())).start();
+        let settings = RoomSettings { capacity: Some(capacity), ..RoomSettings::default() };
+        Room::new("test".to_string(), &settings, config.game, Arc::new(SystemClock::default()), server).unwrap()
     }
 
-}
\ No newline at end of file
+    #[actix_web::test]
+    async fn resuming_from_spectator_takes_the_seat_once() {
+        let mut room = new_room(1);
+        assert_eq!(room.join(1, None), Role::PLAYER);
+        let token = room.resume_token(1).unwrap();
+        room.hold_player(1);
+        assert_eq!(room.join(2, None), Role::SPECTATOR);
+
+        room.resume(&token, 2, None).unwrap();
+        assert_eq!(room.slot_of(2), Some(0));
+        assert!(!room.is_spectator(2));
+        assert_eq!(room.member_ids(), vec![2]);
+    }
+
+    fn identity(player_id: &str) -> Identity {
+        Identity { player_id: player_id.to_string(), name: player_id.to_string() }
+    }
+
+    #[actix_web::test]
+    async fn authenticated_players_resume_by_identity_only() {
+        let mut room = new_room(1);
+        room.join(1, Some(identity("alice")));
+        let token = room.resume_token(1).unwrap();
+        room.hold_player(1);
+
+        assert!(room.resume(&token, 2, Some(&identity("mallory"))).is_err());
+        assert!(room.resume(&token, 2, None).is_err());
+        assert_eq!(room.slot_of(1), Some(0));
+
+        room.resume("", 3, Some(&identity("alice"))).unwrap();
+        assert_eq!(room.slot_of(3), Some(0));
+        assert_eq!(room.identities.get(&3).map(|identity| identity.name.as_str()), Some("alice"));
+    }
+
+    #[actix_web::test]
+    async fn changing_settings_keeps_resume_tokens() {
+        let mut room = new_room(2);
+        room.join(1, None);
+        room.join(2, None);
+        let tokens = [room.resume_token(1).unwrap(), room.resume_token(2).unwrap()];
+
+        let settings = RoomSettings { capacity: Some(3), ..RoomSettings::default() };
+        room.update_settings(1, &settings).unwrap();
+        assert_eq!([room.resume_token(1).unwrap(), room.resume_token(2).unwrap()], tokens);
+
+        room.resume(&tokens[1], 5, None).unwrap();
+        assert_eq!(room.slot_of(5), Some(1));
+    }
+
+    #[actix_web::test]
+    async fn seat_changes_go_into_the_replay_log() {
+        let mut room = new_room(1);
+        room.join(1, None);
+        let token = room.resume_token(1).unwrap();
+        room.hold_player(1);
+        room.resume(&token, 2, None).unwrap();
+        room.disconnect_player(2);
+
+        let log: Vec<_> = room.game_session.lock().unwrap().log.iter().map(|event| event.change).collect();
+        let slot = 0;
+        assert_eq!(log, vec![ReplayChange::JOIN { slot }, ReplayChange::HOLD { slot }, ReplayChange::RESUME { slot }, ReplayChange::LEAVE { slot }]);
+    }
+}
@@ -1,26 +1,251 @@
 use std::time::Instant;
 use actix::*;
-use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web::{http::header, web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
-use rand::Rng;
+use serde::{Deserialize, Serialize};
 
+use crate::auth::{Auth, Identity};
+use crate::config::Config;
+use crate::leaderboard::{Leaderboard, Scope, DEFAULT_LIMIT};
+use crate::protocol::{FrameEncoder, FrameProtocol, SUPPORTED_PROTOCOLS};
 use crate::server;
 use crate::session;
 
+#[derive(Deserialize)]
+pub struct ConnectQuery {
+    token: Option<String>,
+    /// `admin.token`, letting the session moderate chat in any room.
+    admin: Option<String>,
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
 pub async fn chat_server(
     req: HttpRequest,
     stream: web::Payload,
+    query: web::Query<ConnectQuery>,
     srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<Config>,
+    auth: web::Data<Auth>,
 ) -> Result<HttpResponse, Error> {
-    let id = rand::thread_rng().gen_range(0..500);
-    ws::start(
+    let token = query.token.as_deref().or_else(|| bearer_token(&req));
+    let identity = match token {
+        Some(token) if auth.enabled() => match auth.verify(token) {
+            Ok(identity) => Some(identity),
+            Err(reason) => return Ok(HttpResponse::Unauthorized().body(reason)),
+        },
+        _ if auth.required() => return Ok(HttpResponse::Unauthorized().body("token required")),
+        _ => None,
+    };
+    let admin = match query.admin.as_deref() {
+        Some(token) if !config.admin.token.is_empty() && constant_time_eq(token.as_bytes(), config.admin.token.as_bytes()) => true,
+        Some(_) => return Ok(HttpResponse::Unauthorized().body("invalid admin token")),
+        None => false,
+    };
+    let offered = req.headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok());
+    let protocol = FrameProtocol::negotiate(offered);
+    ws::WsResponseBuilder::new(
         session::WsChatSession {
-            id: id,
+            id: None,
             hb: Instant::now(),
             room: "main".to_string(),
             addr: srv.get_ref().clone(),
+            frames: FrameEncoder::new(protocol),
+            snapshots: false,
+            timeouts: config.session.clone(),
+            identity,
+            admin,
         },
         &req,
         stream
     )
-}
\ No newline at end of file
+    .protocols(&SUPPORTED_PROTOCOLS)
+    .start()
+}
+#[derive(Deserialize)]
+pub struct TokenRequest {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    token: String,
+    #[serde(flatten)]
+    identity: Identity,
+}
+
+/// Issues a player token for the requested display name. A caller presenting a valid token
+/// keeps its player id, which is how names are changed and tokens renewed.
+pub async fn issue_token(
+    req: HttpRequest,
+    body: web::Json<TokenRequest>,
+    auth: web::Data<Auth>,
+) -> HttpResponse {
+    if !auth.enabled() {
+        return HttpResponse::NotFound().finish();
+    }
+    let issued = match bearer_token(&req).map(|token| auth.verify(token)) {
+        Some(Ok(identity)) => crate::auth::validate_name(&body.name).map(|name| {
+            let identity = Identity { name, ..identity };
+            (auth.issue(&identity), identity)
+        }),
+        Some(Err(reason)) => return HttpResponse::Unauthorized().body(reason),
+        None => auth.issue_guest(&body.name),
+    };
+    match issued {
+        Ok((token, identity)) => HttpResponse::Ok().json(TokenResponse { token, identity }),
+        Err(reason) => HttpResponse::BadRequest().body(reason),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LeaderboardQuery {
+    limit: Option<usize>,
+    /// `YYYY-MM-DD` in UTC, only used by the daily board; defaults to today.
+    date: Option<String>,
+}
+
+/// Runs the query on the blocking thread pool so SQLite never stalls the async workers.
+async fn top_scores(leaderboard: web::Data<Leaderboard>, scope: Scope, limit: Option<usize>) -> HttpResponse {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT);
+    match web::block(move || leaderboard.top(scope, limit)).await {
+        Ok(Ok(entries)) => HttpResponse::Ok().json(entries),
+        Ok(Err(err)) => {
+            println!("[ERROR] leaderboard query failed: {}", err);
+            HttpResponse::InternalServerError().finish()
+        },
+        Err(err) => {
+            println!("[ERROR] leaderboard query did not run: {}", err);
+            HttpResponse::InternalServerError().finish()
+        },
+    }
+}
+
+pub async fn leaderboard(
+    leaderboard: web::Data<Leaderboard>,
+    query: web::Query<LeaderboardQuery>,
+) -> HttpResponse {
+    top_scores(leaderboard, Scope::Overall, query.limit).await
+}
+
+pub async fn leaderboard_daily(
+    leaderboard: web::Data<Leaderboard>,
+    query: web::Query<LeaderboardQuery>,
+) -> HttpResponse {
+    let LeaderboardQuery { limit, date } = query.into_inner();
+    match date {
+        None => top_scores(leaderboard, Scope::Today, limit).await,
+        Some(day) if Leaderboard::is_day(&day) => top_scores(leaderboard, Scope::Day(day), limit).await,
+        Some(day) => HttpResponse::BadRequest().body(format!("invalid date {day:?}, expected YYYY-MM-DD")),
+    }
+}
+
+pub async fn leaderboard_room(
+    leaderboard: web::Data<Leaderboard>,
+    room: web::Path<String>,
+    query: web::Query<LeaderboardQuery>,
+) -> HttpResponse {
+    top_scores(leaderboard, Scope::Room(room.into_inner()), query.limit).await
+}
+
+/// Checks the `Authorization: Bearer <token>` header against `admin.token`.
+/// Returns the response to send instead when the request is not allowed.
+fn authorize_admin(req: &HttpRequest, config: &Config) -> Option<HttpResponse> {
+    if config.admin.token.is_empty() {
+        return Some(HttpResponse::NotFound().finish());
+    }
+    match bearer_token(req) {
+        Some(token) if constant_time_eq(token.as_bytes(), config.admin.token.as_bytes()) => None,
+        _ => Some(HttpResponse::Unauthorized().finish()),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn mailbox_error(err: MailboxError) -> HttpResponse {
+    println!("[ERROR] admin request failed: {}", err);
+    HttpResponse::InternalServerError().finish()
+}
+
+fn found(found: bool) -> HttpResponse {
+    if found {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+pub async fn admin_list_rooms(
+    req: HttpRequest,
+    srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Some(denied) = authorize_admin(&req, &config) {
+        return denied;
+    }
+    match srv.send(server::ListRooms).await {
+        Ok(rooms) => HttpResponse::Ok().json(rooms),
+        Err(err) => mailbox_error(err),
+    }
+}
+
+pub async fn admin_get_room(
+    req: HttpRequest,
+    room: web::Path<String>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Some(denied) = authorize_admin(&req, &config) {
+        return denied;
+    }
+    match srv.send(server::GetRoom(room.into_inner())).await {
+        Ok(Some(room)) => HttpResponse::Ok().json(room),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(err) => mailbox_error(err),
+    }
+}
+
+pub async fn admin_stop_game(
+    req: HttpRequest,
+    room: web::Path<String>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Some(denied) = authorize_admin(&req, &config) {
+        return denied;
+    }
+    srv.send(server::StopGame(room.into_inner())).await.map_or_else(mailbox_error, found)
+}
+
+pub async fn admin_close_room(
+    req: HttpRequest,
+    room: web::Path<String>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Some(denied) = authorize_admin(&req, &config) {
+        return denied;
+    }
+    srv.send(server::CloseRoom(room.into_inner())).await.map_or_else(mailbox_error, found)
+}
+
+pub async fn admin_kick_session(
+    req: HttpRequest,
+    id: web::Path<usize>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Some(denied) = authorize_admin(&req, &config) {
+        return denied;
+    }
+    srv.send(server::KickSession(id.into_inner())).await.map_or_else(mailbox_error, found)
+}
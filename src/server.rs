@@ -1,18 +1,47 @@
-use std::{collections::{HashMap, HashSet}, ops::Deref, sync::{Arc, Mutex}};
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::{Duration, Instant}};
 use dashmap::DashMap;
+use tokio::task;
 use actix::prelude::*;
-use rand::{self, rngs::ThreadRng, Rng};
-use crate::{game::{frame::Frame, player::Player}, game_session::GameStateType};
-use crate::room::Room;
+use crate::{game::{frame::Frame, snapshot::{GameSummary, Snapshot}}, game_session::GameStateType};
+use crate::protocol::{self, ErrorCode};
+use crate::auth::Identity;
+use crate::chat::{self, Channel, ChatFilter, ChatLine, Command, RateLimit, Speaker};
+use crate::config::{ChatConfig, Config, GameConfig, MatchmakingConfig, RoomsConfig, SessionConfig};
+use crate::game::clock::Clock;
+use crate::game::player::Input;
+use crate::matchmaking::Queue;
+use crate::leaderboard::Leaderboard;
+use crate::room::{Role, Room, RoomInfo, RoomPhase, RoomSettings, MAX_ROOM_CAPACITY};
 
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Message(pub String);
 #[derive(Message)]
+#[rtype(result = "()")]
+pub struct FrameMessage {
+    pub frame: Arc<Frame>,
+    pub snapshot: Arc<Snapshot>,
+    /// Seq of the receiving player's last applied input; `None` for spectators.
+    pub ack: Option<u64>,
+}
+/// Server-side instructions to a session that go beyond forwarding text.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub enum Control {
+    /// Close the socket with the given reason.
+    Kick(String),
+    /// The server placed the session in this room, e.g. after matchmaking.
+    MoveTo(String),
+}
+#[derive(Message)]
 #[rtype(usize)]
 pub struct Connect {
-    pub addr: Recipient<Message>
+    pub addr: Recipient<Message>,
+    pub frame_addr: Recipient<FrameMessage>,
+    pub control_addr: Recipient<Control>,
+    pub identity: Option<Identity>,
+    pub admin: bool,
 }
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -21,15 +50,29 @@ pub struct Disconnect {
 }
 
 pub enum ClientMessageType{
-    MESSAGE(String),
-    MOVEMENT(String),
-    JOIN,
+    /// Chat text for a named channel, or the sender's room channel when none is given.
+    TEXT(Option<String>, String),
+    TYPING(Option<String>),
+    /// Join (true) or leave a global chat channel.
+    SUBSCRIBE(String, bool),
+    /// A player input and the client's sequence number for it.
+    INPUT(Input, Option<u64>),
+    JOIN(RoomSettings),
+    RESUME(String),
+    PAUSE(bool),
+    READY(bool),
+    /// Queue for a match of the given size, two players by default.
+    MATCHMAKE(Option<usize>),
+    DEQUEUE,
+    SETTINGS(RoomSettings),
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ClientMessage {
     pub id: usize,
+    /// Type of the client command this came from, echoed back if it is rejected.
+    pub command: &'static str,
     pub room: String,
     pub msg_type: ClientMessageType,
 }
@@ -38,42 +81,437 @@ pub struct ClientMessage {
 #[rtype(result= "()")]
 pub struct GameSessionMessage {
     pub room_id: String,
-    pub frame: Arc<Mutex<Option<Frame>>>,
+    pub frame: Arc<Frame>,
     pub state: GameStateType,
-    pub player1_sessionid: usize,
-    pub player2_sessionid: usize,
+    pub player_session_ids: Vec<usize>,
+    pub eliminated: Vec<usize>,
+    pub wave_started: Option<usize>,
+    /// Whole seconds left on the lobby countdown, set each time it ticks over.
+    pub countdown: Option<u64>,
+    pub snapshot: Arc<Snapshot>,
+    pub summary: Option<Arc<GameSummary>>,
 }
 
+const MATCHMAKING_INTERVAL: Duration = Duration::from_secs(1);
+const ROOM_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Message)]
+#[rtype(result = "Vec<RoomInfo>")]
+pub struct ListRooms;
+
+#[derive(Message)]
+#[rtype(result = "Option<RoomInfo>")]
+pub struct GetRoom(pub String);
+
+/// Admin request to end a room's game; the result is false when the room does not exist.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct StopGame(pub String);
+
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct CloseRoom(pub String);
+
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct KickSession(pub usize);
+
 #[derive(Debug)]
 pub struct ChatServer{
     sessions: HashMap<usize, Recipient<Message>>,
-    rng:  ThreadRng,
-    rooms: DashMap<String , HashSet<usize>>,
+    frame_sessions: HashMap<usize, Recipient<FrameMessage>>,
+    control_sessions: HashMap<usize, Recipient<Control>>,
+    identities: HashMap<usize, Identity>,
+    /// Sessions connected with the admin token.
+    admins: HashSet<usize>,
+    /// Last session id handed out; ids start at 1 and are never reused while a session holds them.
+    last_session_id: usize,
+    /// Chat channels by name: the configured global ones plus one per game room.
+    channels: HashMap<String, Channel>,
     game_rooms: DashMap<String , Room>,
-    active_games: DashMap<String, bool>
+    active_games: DashMap<String, bool>,
+    game_config: GameConfig,
+    session_config: SessionConfig,
+    matchmaking: MatchmakingConfig,
+    rooms_config: RoomsConfig,
+    chat_config: ChatConfig,
+    filter: Box<dyn ChatFilter>,
+    rate_limits: HashMap<Speaker, RateLimit>,
+    /// Typing indicators are throttled separately so they do not eat into the TEXT budget.
+    typing_limits: HashMap<Speaker, RateLimit>,
+    /// Muted players and sessions and when their mute ends. Player mutes outlive the session.
+    mutes: HashMap<Speaker, Instant>,
+    queue: Queue,
+    /// Sessions that asked to matchmake and are waiting on their rating lookup.
+    pending_ratings: HashSet<usize>,
+    last_match_id: usize,
+    leaderboard: Arc<Leaderboard>,
+    /// Clock every room's game runs on.
+    clock: Arc<dyn Clock>,
 }
 
 impl ChatServer {
-    pub fn new() -> ChatServer {
-        let rooms = DashMap::new();
+    pub fn new(config: &Config, leaderboard: Arc<Leaderboard>, filter: Box<dyn ChatFilter>, clock: Arc<dyn Clock>) -> ChatServer {
+        let channels = config.chat.channels.iter()
+            .map(|name| (name.clone(), Channel::default()))
+            .collect();
         let game_rooms = DashMap::new();
-        rooms.insert("main".to_string(), HashSet::new());
         let active_games = DashMap::new();
         Self {
             sessions: HashMap::new(),
-            rng: rand::thread_rng(),
-            rooms,
+            frame_sessions: HashMap::new(),
+            control_sessions: HashMap::new(),
+            identities: HashMap::new(),
+            admins: HashSet::new(),
+            last_session_id: 0,
+            channels,
             game_rooms,
             active_games,
+            game_config: config.game.clone(),
+            session_config: config.session.clone(),
+            matchmaking: config.matchmaking.clone(),
+            rooms_config: config.rooms.clone(),
+            chat_config: config.chat.clone(),
+            filter,
+            rate_limits: HashMap::new(),
+            typing_limits: HashMap::new(),
+            mutes: HashMap::new(),
+            queue: Queue::default(),
+            pending_ratings: HashSet::new(),
+            last_match_id: 0,
+            leaderboard,
+            clock,
+        }
+    }
+
+    fn enqueue(&mut self, id: usize, capacity: usize, rating: Option<f64>, addr: Addr<ChatServer>) {
+        self.queue.enqueue(id, capacity, rating);
+        println!("[INFO] Session {} queued for a {} player match (rating {:?})", id, capacity, rating);
+        self.send_to(id, &protocol::encode_matchmake(true));
+        self.run_matchmaking(addr);
+    }
+
+    /// Removes the room, telling anyone still in it why. Returns false if there was no such room.
+    fn close_room(&mut self, name: &str, reason: &str) -> bool {
+        let Some((_, mut room)) = self.game_rooms.remove(name) else {
+            return false;
+        };
+        room.close();
+        println!("[INFO] Room [{}] {:?}: {}", room.name, room.phase(), reason);
+        if self.active_games.remove(name).is_some() {
+            println!("[INFO] Active games count : {}", self.active_games.len());
+        }
+        self.broadcast(&room, &protocol::encode_notice(reason));
+        self.channels.remove(&chat::room_channel(name));
+        true
+    }
+
+    /// Starts timing rooms that have nobody connected or whose game is over, and closes those idle for too long.
+    /// Also forgets mutes and rate limits that have run out.
+    fn sweep_rooms(&mut self) {
+        let mut expired = Vec::new();
+        for mut room in self.game_rooms.iter_mut() {
+            let connected = room.member_ids().iter().any(|id| self.sessions.contains_key(id));
+            if connected && room.phase() != RoomPhase::FINISHED {
+                room.idle_since = None;
+                continue;
+            }
+            let since = *room.idle_since.get_or_insert_with(std::time::Instant::now);
+            if since.elapsed() >= self.rooms_config.idle_timeout() {
+                expired.push(room.name.clone());
+            }
+        }
+        for name in expired {
+            self.close_room(&name, "room closed after being idle");
         }
+        let now = Instant::now();
+        self.mutes.retain(|_, until| *until > now);
+        let window = self.chat_config.rate_window();
+        self.rate_limits.retain(|_, limit| !limit.is_idle(window));
+        self.typing_limits.retain(|_, limit| !limit.is_idle(window));
     }
 
-    fn send_message(&self, room: &str, message: &str) {
-        if let Some(sessions) = self.rooms.get(room) {
-            for id in sessions.iter() {
-                if let Some(addr) = self.sessions.get(&id) {
-                    addr.do_send(Message(message.to_owned()))
+    /// Places every group the queue can form into a fresh room, then gives
+    /// sessions that waited past the timeout a solo game.
+    fn run_matchmaking(&mut self, addr: Addr<ChatServer>) {
+        while let Some((capacity, sessions)) = self.queue.next_match(&self.matchmaking) {
+            self.create_match(capacity, sessions, addr.clone());
+        }
+        for session_id in self.queue.expired(self.matchmaking.timeout()) {
+            self.send_to(session_id, &protocol::encode_notice("no match found, starting a solo game"));
+            self.create_match(1, vec![session_id], addr.clone());
+        }
+    }
+
+    fn create_match(&mut self, capacity: usize, sessions: Vec<usize>, addr: Addr<ChatServer>) {
+        let name = loop {
+            self.last_match_id += 1;
+            let name = format!("match-{}", self.last_match_id);
+            if !self.game_rooms.contains_key(&name) {
+                break name;
+            }
+        };
+        let settings = RoomSettings { capacity: Some(capacity), ..RoomSettings::default() };
+        let mut room = match Room::new(name.clone(), &settings, self.game_config.clone(), self.clock.clone(), addr) {
+            Ok(room) => room,
+            Err(reason) => {
+                for session_id in sessions {
+                    self.reject(session_id, "MATCHMAKE", reason.clone());
                 }
+                return;
+            },
+        };
+        println!("[INFO] Matchmaking placed {:?} in room [{}]", sessions, name);
+        for session_id in sessions {
+            let Some(control) = self.control_sessions.get(&session_id).cloned() else {
+                continue;
+            };
+            self.leave_rooms(session_id, None);
+            control.do_send(Control::MoveTo(name.clone()));
+            let role = room.join(session_id, self.identities.get(&session_id).cloned());
+            self.send_role(&room, session_id, role);
+            self.enter_room_channel(session_id, &name);
+        }
+        self.broadcast_lobby(&room);
+        self.game_rooms.insert(name, room);
+    }
+
+    fn allocate_session_id(&mut self) -> usize {
+        loop {
+            self.last_session_id = self.last_session_id.checked_add(1).unwrap_or(1);
+            if !self.sessions.contains_key(&self.last_session_id) {
+                return self.last_session_id;
+            }
+        }
+    }
+
+    fn broadcast(&self, room: &Room, message: &str) {
+        for session_id in room.member_ids() {
+            self.send_to(session_id, message);
+        }
+    }
+
+    fn broadcast_lobby(&self, room: &Room) {
+        if room.in_lobby() {
+            self.broadcast(room, &protocol::encode_lobby(&room.lobby()));
+        }
+    }
+
+    /// Lets the room know the game was paused or resumed.
+    fn announce_pause(&self, room: &Room, changed: Option<bool>) {
+        if let Some(paused) = changed {
+            self.broadcast(room, &protocol::encode_pause(paused));
+        }
+    }
+
+    /// Adds the session to a channel and replays its history to it.
+    fn join_channel(&mut self, id: usize, name: &str) {
+        let channel = self.channels.entry(name.to_string()).or_default();
+        channel.members.insert(id);
+        let history = protocol::encode_history(name, channel.history());
+        self.send_to(id, &history);
+    }
+
+    /// Moves the session into `room`'s channel, out of any other room's.
+    fn enter_room_channel(&mut self, id: usize, room: &str) {
+        let name = chat::room_channel(room);
+        for (channel_name, channel) in self.channels.iter_mut() {
+            if *channel_name != name && channel_name.starts_with(chat::ROOM_CHANNEL_PREFIX) {
+                channel.members.remove(&id);
+            }
+        }
+        if !self.channels.get(&name).is_some_and(|channel| channel.members.contains(&id)) {
+            self.join_channel(id, &name);
+        }
+    }
+
+    /// Name shown for the session in chat: its identity's name, or a guest name.
+    fn display_name(&self, id: usize) -> String {
+        match self.identities.get(&id) {
+            Some(identity) => identity.name.clone(),
+            None => format!("Guest {id}"),
+        }
+    }
+
+    /// The channel a chat message is for, `room`'s when none is given. Only members may post to a channel.
+    fn chat_channel(&self, id: usize, room: &str, channel: Option<String>) -> Result<String, String> {
+        let name = channel.unwrap_or_else(|| chat::room_channel(room));
+        match self.channels.get(&name) {
+            Some(channel) if channel.members.contains(&id) => Ok(name),
+            _ => Err(format!("not a member of channel {name:?}")),
+        }
+    }
+
+    fn send_to_channel(&self, name: &str, message: &str) {
+        if let Some(channel) = self.channels.get(name) {
+            for member in channel.members.iter() {
+                self.send_to(*member, message);
+            }
+        }
+    }
+
+    /// Stamps the text with its sender and time, delivers it to the channel and keeps it in the history.
+    fn send_text(&mut self, id: usize, room: &str, channel: Option<String>, text: String) -> Result<(), String> {
+        let channel = self.chat_channel(id, room, channel)?;
+        let line = ChatLine { from: self.display_name(id), from_id: id, channel, text, ts: chat::now_millis() };
+        self.send_to_channel(&line.channel, &protocol::encode_text(&line));
+        let limit = self.chat_config.history;
+        if let Some(channel) = self.channels.get_mut(&line.channel) {
+            channel.record(line, limit);
+        }
+        Ok(())
+    }
+
+    /// Relays a typing indicator to the channel, throttled like TEXT but on its own budget.
+    fn send_typing(&mut self, id: usize, room: &str, channel: Option<String>) -> Result<(), String> {
+        let typing_limit = self.typing_limits.entry(self.speaker(id)).or_default();
+        if !typing_limit.allow(self.chat_config.rate_limit_messages, self.chat_config.rate_window()) {
+            return Err("you are sending messages too fast".to_string());
+        }
+        let channel = self.chat_channel(id, room, channel)?;
+        self.send_to_channel(&channel, &protocol::encode_typing(&channel, &self.display_name(id), id));
+        Ok(())
+    }
+
+    /// Runs a TEXT message through the rate limit, moderation commands, mutes and the word filter.
+    fn handle_text(&mut self, id: usize, room: &str, channel: Option<String>, text: &str) -> Result<(), String> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err("message must not be empty".to_string());
+        }
+        if text.chars().count() > self.chat_config.max_message_len {
+            return Err(format!("message must be at most {} characters", self.chat_config.max_message_len));
+        }
+        let rate_limit = self.rate_limits.entry(self.speaker(id)).or_default();
+        if !rate_limit.allow(self.chat_config.rate_limit_messages, self.chat_config.rate_window()) {
+            return Err("you are sending messages too fast".to_string());
+        }
+        if text.starts_with('/') {
+            return self.moderate(id, Command::parse(text)?);
+        }
+        if let Some(left) = self.muted_for(id) {
+            return Err(format!("you are muted for another {} seconds", left.as_secs().max(1)));
+        }
+        let text = self.filter.apply(text)?;
+        self.send_text(id, room, channel, text)
+    }
+
+    /// Whom the session's mutes and rate limits are kept under.
+    fn speaker(&self, id: usize) -> Speaker {
+        match self.identities.get(&id) {
+            Some(identity) => Speaker::PLAYER(identity.player_id.clone()),
+            None => Speaker::SESSION(id),
+        }
+    }
+
+    /// Time left on the session's mute, forgetting mutes that have run out.
+    fn muted_for(&mut self, id: usize) -> Option<Duration> {
+        let speaker = self.speaker(id);
+        let until = *self.mutes.get(&speaker)?;
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            self.mutes.remove(&speaker);
+            return None;
+        }
+        Some(left)
+    }
+
+    /// Applies a moderation command. Admins may target anyone, room hosts only members of their room.
+    fn moderate(&mut self, id: usize, command: Command) -> Result<(), String> {
+        let target = command.target();
+        if !self.sessions.contains_key(&target) {
+            return Err(format!("no session {target}"));
+        }
+        let admin = self.admins.contains(&id);
+        let host = self.game_rooms.iter().any(|room| room.is_host(id) && room.member_ids().contains(&target));
+        if !admin && !host {
+            return Err("only the room host or an administrator can do that".to_string());
+        }
+        let name = self.display_name(target);
+        let notice = match command {
+            Command::MUTE(_, secs) => {
+                let now = Instant::now();
+                let until = chat::mute_until(secs, &self.chat_config, now)?;
+                let duration = until - now;
+                self.mutes.insert(self.speaker(target), until);
+                let muted = format!("you were muted for {} seconds", duration.as_secs());
+                self.send_to(target, &protocol::encode_notice(&muted));
+                format!("{name} muted for {} seconds", duration.as_secs())
+            },
+            Command::UNMUTE(_) => {
+                if self.mutes.remove(&self.speaker(target)).is_some() {
+                    self.send_to(target, &protocol::encode_notice("you are no longer muted"));
+                }
+                format!("{name} unmuted")
+            },
+            Command::KICK(_) => {
+                let reason = if admin { "kicked by an administrator" } else { "kicked by the room host" };
+                self.kick_session(target, reason);
+                format!("{name} kicked")
+            },
+        };
+        println!("[INFO] Session {} moderation: {}", id, notice);
+        self.send_to(id, &protocol::encode_notice(&notice));
+        Ok(())
+    }
+
+    /// Closes the session's socket and frees its place in any room. Returns false if it is not connected.
+    fn kick_session(&mut self, id: usize, reason: &str) -> bool {
+        let Some(kick) = self.control_sessions.get(&id) else {
+            return false;
+        };
+        kick.do_send(Control::Kick(reason.to_string()));
+        println!("[INFO] Session {} {}", id, reason);
+        self.leave_rooms(id, None);
+        true
+    }
+
+    /// Frees the session's seat or spectator place in every room but `except`, and takes it
+    /// out of those rooms' chat channels, so it is only ever a member of one room.
+    fn leave_rooms(&mut self, id: usize, except: Option<&str>) {
+        let mut left = Vec::new();
+        for mut room in self.game_rooms.iter_mut() {
+            if except == Some(room.name.as_str()) {
+                continue;
+            }
+            if room.is_spectator(id) {
+                room.disconnect_spectator(id);
+            } else if room.slot_of(id).is_some() {
+                if let Some(promoted) = room.disconnect_player(id) {
+                    self.send_role(&room, promoted, Role::PLAYER);
+                }
+                let changed = room.sync_pause();
+                self.announce_pause(&room, changed);
+                self.broadcast_lobby(&room);
+            } else {
+                continue;
+            }
+            left.push(chat::room_channel(&room.name));
+        }
+        for name in left {
+            if let Some(channel) = self.channels.get_mut(&name) {
+                channel.members.remove(&id);
+            }
+        }
+    }
+
+    /// Tells the session why its command was refused.
+    fn reject(&self, id: usize, command: &str, reason: String) {
+        self.send_to(id, &protocol::encode_error(ErrorCode::REJECTED, Some(command), &reason));
+    }
+
+    fn send_to(&self, id: usize, message: &str) {
+        if let Some(addr) = self.sessions.get(&id) {
+            addr.do_send(Message(message.to_owned()))
+        }
+    }
+
+    /// Tells the session its role in `room`; players also get the RESUME message to reconnect with.
+    fn send_role(&self, room: &Room, id: usize, role: Role) {
+        self.send_to(id, &protocol::encode_role(role));
+        if role == Role::PLAYER {
+            if let Some(token) = room.resume_token(id) {
+                self.send_to(id, &protocol::encode_resume(&room.name, &token));
             }
         }
     }
@@ -81,13 +519,28 @@ impl ChatServer {
 
 impl Actor for ChatServer {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(MATCHMAKING_INTERVAL, |act, ctx| act.run_matchmaking(ctx.address()));
+        ctx.run_interval(ROOM_SWEEP_INTERVAL, |act, _| act.sweep_rooms());
+    }
 }
 
 impl Handler<Connect> for ChatServer {
     type Result = usize;
     fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
-        let id = self.rng.gen::<usize>();
+        let id = self.allocate_session_id();
         self.sessions.insert(id, msg.addr);
+        self.frame_sessions.insert(id, msg.frame_addr);
+        self.control_sessions.insert(id, msg.control_addr);
+        if let Some(identity) = msg.identity {
+            println!("[INFO] Session {} authenticated as {} ({})", id, identity.name, identity.player_id);
+            self.identities.insert(id, identity);
+        }
+        if msg.admin {
+            println!("[INFO] Session {} connected as admin", id);
+            self.admins.insert(id);
+        }
         id
     }
 }
@@ -95,14 +548,37 @@ impl Handler<Connect> for ChatServer {
 impl Handler<Disconnect> for ChatServer {
     type Result = ();
     fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) -> Self::Result {
+        self.frame_sessions.remove(&msg.id);
+        self.control_sessions.remove(&msg.id);
+        self.identities.remove(&msg.id);
+        self.admins.remove(&msg.id);
+        // Player mutes and rate limits stay until they run out; see `sweep_rooms`.
+        self.rate_limits.remove(&Speaker::SESSION(msg.id));
+        self.typing_limits.remove(&Speaker::SESSION(msg.id));
+        self.mutes.remove(&Speaker::SESSION(msg.id));
+        self.queue.remove(msg.id);
+        self.pending_ratings.remove(&msg.id);
         if self.sessions.remove(&msg.id).is_some() {
             println!("Player disconnected 1");
-            for mut v in self.rooms.iter_mut() {
-                let ( _name,  sessions) =  v.pair_mut();
-                if sessions.remove(&msg.id) {
-
+            for channel in self.channels.values_mut() {
+                channel.members.remove(&msg.id);
+            }
+        }
+        // Running games hold a dropped player's slot for a resume; lobbies simply free it.
+        for mut room in self.game_rooms.iter_mut() {
+            if !room.in_lobby() {
+                continue;
+            }
+            if room.is_spectator(msg.id) {
+                room.disconnect_spectator(msg.id);
+            } else if room.slot_of(msg.id).is_some() {
+                if let Some(promoted) = room.disconnect_player(msg.id) {
+                    self.send_role(&room, promoted, Role::PLAYER);
                 }
+            } else {
+                continue;
             }
+            self.broadcast_lobby(&room);
         }
     }
 }
@@ -111,15 +587,160 @@ impl Handler<ClientMessage> for ChatServer {
     type Result = ();
     fn handle(&mut self, msg: ClientMessage, ctx: &mut Self::Context) -> Self::Result {
         match msg.msg_type {
-            ClientMessageType::MESSAGE(text_message) => self.send_message(&msg.room, &text_message),
-            ClientMessageType::MOVEMENT(mov)=> {
-                    if let Some(mut room) = self.game_rooms.get_mut(msg.room.as_str()){
-                        room.handle_player_input(&msg.id, mov.as_str());
+            ClientMessageType::TEXT(channel, text) => {
+                if let Err(reason) = self.handle_text(msg.id, &msg.room, channel, &text) {
+                    self.reject(msg.id, msg.command, reason);
+                }
+            },
+            ClientMessageType::TYPING(channel) => {
+                // Typing indicators are best effort, so failures are not reported back.
+                if self.muted_for(msg.id).is_none() {
+                    let _ = self.send_typing(msg.id, &msg.room, channel);
+                }
+            },
+            ClientMessageType::SUBSCRIBE(name, subscribe) => {
+                if !self.chat_config.channels.contains(&name) {
+                    self.reject(msg.id, msg.command, format!("unknown channel {name:?}"));
+                } else if subscribe {
+                    self.join_channel(msg.id, &name);
+                } else if let Some(channel) = self.channels.get_mut(&name) {
+                    channel.members.remove(&msg.id);
+                }
+            },
+            ClientMessageType::INPUT(input, seq)=> {
+                let Some(mut room) = self.game_rooms.get_mut(msg.room.as_str()) else {
+                    self.reject(msg.id, msg.command, "not in a room".to_string());
+                    return;
+                };
+                if let Err(reason) = room.handle_player_input(&msg.id, input, seq) {
+                    self.reject(msg.id, msg.command, reason);
+                }
+            },
+            ClientMessageType::MATCHMAKE(capacity) => {
+                let capacity = capacity.unwrap_or(2).clamp(1, MAX_ROOM_CAPACITY);
+                if self.game_rooms.iter().any(|room| room.slot_of(msg.id).is_some() && room.phase() == RoomPhase::PLAYING) {
+                    self.reject(msg.id, msg.command, "leave your game before looking for a match".to_string());
+                    return;
+                }
+                let Some(identity) = self.identities.get(&msg.id) else {
+                    self.enqueue(msg.id, capacity, None, ctx.address());
+                    return;
+                };
+                // The rating comes from SQLite, so look it up on the blocking pool and queue once it arrives.
+                let leaderboard = self.leaderboard.clone();
+                let player_id = identity.player_id.clone();
+                self.pending_ratings.insert(msg.id);
+                let lookup = task::spawn_blocking(move || leaderboard.rating(&player_id).unwrap_or_else(|err| {
+                    println!("[ERROR] unable to read rating for {}: {}", player_id, err);
+                    None
+                }));
+                ctx.spawn(lookup.into_actor(self).map(move |rating, act, ctx| {
+                    // A DEQUEUE, JOIN or disconnect while the lookup ran cancels the request.
+                    if act.pending_ratings.remove(&msg.id) {
+                        act.enqueue(msg.id, capacity, rating.unwrap_or(None), ctx.address());
                     }
+                }));
+            },
+            ClientMessageType::DEQUEUE => {
+                let pending = self.pending_ratings.remove(&msg.id);
+                if self.queue.remove(msg.id) || pending {
+                    self.send_to(msg.id, &protocol::encode_matchmake(false));
+                }
+            },
+            ClientMessageType::JOIN(settings) => {
+                self.queue.remove(msg.id);
+                self.pending_ratings.remove(&msg.id);
+                let finished = self.game_rooms.get(msg.room.as_str())
+                    .filter(|room| room.phase() == RoomPhase::FINISHED)
+                    .map(|room| room.settings());
+                let settings = match finished {
+                    Some(previous) => {
+                        self.close_room(&msg.room, "game over, the room is starting a new game");
+                        RoomSettings {
+                            capacity: settings.capacity.or(previous.capacity),
+                            cols: settings.cols.or(previous.cols),
+                            rows: settings.rows.or(previous.rows),
+                            level: settings.level.or(previous.level),
+                            // A new game gets a fresh seed unless the joiner asks for one.
+                            seed: settings.seed,
+                        }
+                    },
+                    None => settings,
+                };
+                if !self.game_rooms.contains_key(msg.room.as_str()) {
+                    match Room::new(msg.room.clone(), &settings, self.game_config.clone(), self.clock.clone(), ctx.address()) {
+                        Ok(room) => {
+                            self.game_rooms.insert(msg.room.clone(), room);
+                        },
+                        Err(reason) => {
+                            self.reject(msg.id, msg.command, reason);
+                            return;
+                        },
+                    }
+                }
+                self.leave_rooms(msg.id, Some(&msg.room));
+                let mut room = self.game_rooms.get_mut(msg.room.as_str()).unwrap();
+                let role = room.join(msg.id, self.identities.get(&msg.id).cloned());
+                self.send_role(&room, msg.id, role);
+                self.broadcast_lobby(&room);
+                drop(room);
+                self.enter_room_channel(msg.id, &msg.room);
+            },
+            ClientMessageType::READY(ready) => {
+                let Some(mut room) = self.game_rooms.get_mut(msg.room.as_str()) else {
+                    self.reject(msg.id, msg.command, "not in a room".to_string());
+                    return;
+                };
+                match room.set_ready(msg.id, ready) {
+                    Ok(()) => self.broadcast_lobby(&room),
+                    Err(reason) => self.reject(msg.id, msg.command, reason),
+                }
+            },
+            ClientMessageType::SETTINGS(settings) => {
+                let Some(mut room) = self.game_rooms.get_mut(msg.room.as_str()) else {
+                    self.reject(msg.id, msg.command, "not in a room".to_string());
+                    return;
+                };
+                match room.update_settings(msg.id, &settings) {
+                    Ok(promoted) => {
+                        for session_id in promoted {
+                            self.send_role(&room, session_id, Role::PLAYER);
+                        }
+                        self.broadcast_lobby(&room);
+                    },
+                    Err(reason) => self.reject(msg.id, msg.command, reason),
+                }
             },
-            ClientMessageType::JOIN => {
-                let mut room =  self.game_rooms.entry(msg.room.clone()).or_insert(Room::new(msg.room.clone(), ctx.address()));
-                room.join(msg.id);
+            ClientMessageType::PAUSE(pause) => {
+                let Some(mut room) = self.game_rooms.get_mut(msg.room.as_str()) else {
+                    self.reject(msg.id, msg.command, "not in a room".to_string());
+                    return;
+                };
+                let result = if pause { room.pause(msg.id) } else { room.vote_resume(msg.id) };
+                match result {
+                    Ok(Some(paused)) => self.announce_pause(&room, Some(paused)),
+                    Ok(None) if !pause => {
+                        let (votes, needed) = room.resume_votes();
+                        let notice = format!("{votes} of {needed} players ready to resume");
+                        self.broadcast(&room, &protocol::encode_notice(&notice));
+                    },
+                    Ok(None) => (),
+                    Err(reason) => self.reject(msg.id, msg.command, reason),
+                }
+            },
+            ClientMessageType::RESUME(token) => {
+                let resumed = match self.game_rooms.get_mut(msg.room.as_str()) {
+                    Some(mut room) => room.resume(&token, msg.id, self.identities.get(&msg.id)).map(|_| {
+                        self.send_role(&room, msg.id, Role::PLAYER);
+                        let changed = room.sync_pause();
+                        self.announce_pause(&room, changed);
+                    }),
+                    None => Err(format!("unknown room {:?}", msg.room)),
+                };
+                match resumed {
+                    Ok(()) => self.enter_room_channel(msg.id, &msg.room),
+                    Err(reason) => self.reject(msg.id, msg.command, reason),
+                }
             },
         }
     }
@@ -132,13 +753,38 @@ impl Handler<GameSessionMessage> for ChatServer {
 
         match self.game_rooms.get_mut(msg.room_id.as_str()){
             Some(mut room) => {
+                for session_id in msg.eliminated.iter() {
+                    self.send_role(&room, *session_id, Role::SPECTATOR);
+                }
+
+                if let Some(summary) = &msg.summary {
+                    // Saving hits SQLite, so keep it off the actor; nothing here waits on the result.
+                    let leaderboard = self.leaderboard.clone();
+                    let (room_id, saved) = (msg.room_id.clone(), summary.clone());
+                    task::spawn_blocking(move || {
+                        if let Err(err) = leaderboard.record(&room_id, &saved) {
+                            println!("[ERROR] Room [{}] unable to save game to leaderboard: {}", room_id, err);
+                        }
+                    });
+                    let summary = protocol::encode_summary(summary);
+                    self.broadcast(&room, &summary);
+                }
+
+                if let Some(secs) = msg.countdown {
+                    self.broadcast(&room, &protocol::encode_countdown(secs));
+                }
+
+                if let Some(wave) = msg.wave_started {
+                    self.broadcast(&room, &protocol::encode_wave(wave));
+                }
+
                 match msg.state {
-                    GameStateType::IDLE => (),
+                    GameStateType::IDLE | GameStateType::PAUSE => (),
                     GameStateType::START => {
-                        println!("[INFO] GAME STARTED Room [{}]", msg.room_id.as_str());
-                        self.active_games.insert(msg.room_id.clone(), true);
-                        println!("[INFO] Active games count : {}", self.active_games.len());
-                        return ()
+                        if self.active_games.insert(msg.room_id.clone(), true).is_none() {
+                            println!("[INFO] GAME STARTED Room [{}]", msg.room_id.as_str());
+                            println!("[INFO] Active games count : {}", self.active_games.len());
+                        }
                     },
                     GameStateType::STOP => {
                         println!("[INFO] GAME STOPPED Room [{}]", msg.room_id.as_str());
@@ -146,66 +792,220 @@ impl Handler<GameSessionMessage> for ChatServer {
                         println!("[INFO] Active games count : {}", self.active_games.len());
                     },
                     GameStateType::WIN => {
-                        println!("[INFO] GAME WON Room [{}]", msg.room_id.as_str());
+                        println!("[INFO] GAME WON Room [{}] wave {} score {}", msg.room_id.as_str(), msg.snapshot.wave, msg.snapshot.score);
                         room.stop_update_loop();
                         self.active_games.remove(msg.room_id.as_str());
                         println!("[INFO] Active games count : {}", self.active_games.len());
                     },
                     GameStateType::LOSE => {
-                        println!("[INFO] GAME LOST Room [{}]", msg.room_id.as_str());
+                        println!("[INFO] GAME LOST Room [{}] wave {} score {}", msg.room_id.as_str(), msg.snapshot.wave, msg.snapshot.score);
                         room.stop_update_loop();
                         self.active_games.remove(msg.room_id.as_str());
                         println!("[INFO] Active games count : {}", self.active_games.len());
                     },
                 }
 
-                match msg.frame.lock() {                    
-                    Ok(frame) => {
-                        let mut player1_connected = false;
-                        let mut player2_connected = false;
-
-                        let res  = serde_json::to_string(frame.deref()).unwrap();
+                let mut any_connected = false;
 
-                        match self.sessions.get(&msg.player1_sessionid) {
-                            Some(session) => {
-                                player1_connected = true;
-                                session.do_send(Message{0:res.clone()})
-                            },
-                            None => {
-                                if msg.player1_sessionid != 0 {
-                                    println!("[INFO] Room [{}] Player 1 disconnected", room.name);
-                                    room.disconnect_player(msg.player1_sessionid);
+                let update = |ack| FrameMessage { frame: msg.frame.clone(), snapshot: msg.snapshot.clone(), ack };
 
-                                }
-                            },
-                        }
+                for session_id in msg.player_session_ids.iter() {
+                    match self.frame_sessions.get(session_id) {
+                        Some(session) => {
+                            any_connected = true;
+                            session.do_send(update(msg.snapshot.seq_of(*session_id)));
+                        },
+                        None => {
+                            room.hold_player(*session_id);
+                        },
+                    }
+                }
 
-                        match self.sessions.get(&msg.player2_sessionid) {
-                            Some(session) => {
-                                player2_connected = true;                                
-                                session.do_send(Message{0:res.clone()});
-                            },
-                            None => {
-                                if msg.player2_sessionid != 0 {
-                                    println!("[INFO] Room [{}] Player 2 disconnected", room.name);
-                                    room.disconnect_player(msg.player2_sessionid);
-                                    player2_connected = false;
-                                }
-                            },
-                        }
+                for session_id in room.expired_holds(self.session_config.resume_grace()) {
+                    println!("[INFO] Room [{}] Player {} did not resume in time", room.name, session_id);
+                    if let Some(promoted) = room.disconnect_player(session_id) {
+                        self.send_role(&room, promoted, Role::PLAYER);
+                    }
+                }
 
-                        if !(player1_connected || player2_connected){
-                            println!("[INFO] GAME Room Empty [{}]", msg.room_id.as_str());
-                            self.active_games.remove(msg.room_id.as_str());
-                            println!("[INFO] Active games count : {}", self.active_games.len());
-                        }
+                let changed = room.sync_pause();
+                self.announce_pause(&room, changed);
 
+                for session_id in room.spectators.clone() {
+                    match self.frame_sessions.get(&session_id) {
+                        Some(session) => session.do_send(update(None)),
+                        None => room.disconnect_spectator(session_id),
+                    }
+                }
 
-                    },
-                    Err(_) => print!("[ERROR] No frame data for room [{}]", msg.room_id.clone()),
+                if !any_connected && room.held_count() == 0 {
+                    println!("[INFO] GAME Room Empty [{}]", msg.room_id.as_str());
+                    self.active_games.remove(msg.room_id.as_str());
+                    println!("[INFO] Active games count : {}", self.active_games.len());
                 }
             },
             None => println!("[ERROR] ChatServer : missing game room from message [{}]", msg.room_id.clone()),
         }
     }
-}
\ No newline at end of file
+}
+impl Handler<ListRooms> for ChatServer {
+    type Result = MessageResult<ListRooms>;
+    fn handle(&mut self, _: ListRooms, _: &mut Self::Context) -> Self::Result {
+        let mut rooms: Vec<RoomInfo> = self.game_rooms.iter().map(|room| room.info()).collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        MessageResult(rooms)
+    }
+}
+
+impl Handler<GetRoom> for ChatServer {
+    type Result = Option<RoomInfo>;
+    fn handle(&mut self, msg: GetRoom, _: &mut Self::Context) -> Self::Result {
+        self.game_rooms.get(msg.0.as_str()).map(|room| room.info())
+    }
+}
+
+impl Handler<StopGame> for ChatServer {
+    type Result = bool;
+    fn handle(&mut self, msg: StopGame, _: &mut Self::Context) -> Self::Result {
+        match self.game_rooms.get_mut(msg.0.as_str()) {
+            Some(mut room) => {
+                room.stop_game();
+                self.active_games.remove(msg.0.as_str());
+                println!("[INFO] Active games count : {}", self.active_games.len());
+                let notice = protocol::encode_notice("game stopped by an administrator");
+                self.broadcast(&room, &notice);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+impl Handler<CloseRoom> for ChatServer {
+    type Result = bool;
+    fn handle(&mut self, msg: CloseRoom, _: &mut Self::Context) -> Self::Result {
+        self.close_room(&msg.0, "room closed by an administrator")
+    }
+}
+
+impl Handler<KickSession> for ChatServer {
+    type Result = bool;
+    fn handle(&mut self, msg: KickSession, _: &mut Self::Context) -> Self::Result {
+        self.kick_session(msg.0, "kicked by an administrator")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::WordFilter;
+    use crate::game::clock::SystemClock;
+
+    fn new_server() -> ChatServer {
+        let leaderboard = Arc::new(Leaderboard::open(":memory:").unwrap());
+        ChatServer::new(&Config::default(), leaderboard, Box::new(WordFilter::default()), Arc::new(SystemClock::default()))
+    }
+
+    fn join(server: &mut ChatServer, ctx: &mut Context<ChatServer>, id: usize, room: &str) {
+        let settings = RoomSettings { capacity: Some(2), ..RoomSettings::default() };
+        let msg = ClientMessage { id, command: "JOIN", room: room.to_string(), msg_type: ClientMessageType::JOIN(settings) };
+        server.handle(msg, ctx);
+    }
+
+    #[actix_web::test]
+    async fn joining_another_room_leaves_the_previous_one() {
+        let mut server = new_server();
+        let mut ctx = Context::new();
+        join(&mut server, &mut ctx, 1, "a");
+        join(&mut server, &mut ctx, 2, "a");
+        assert_eq!(server.game_rooms.get("a").unwrap().slot_of(1), Some(0));
+
+        join(&mut server, &mut ctx, 1, "b");
+        let a = server.game_rooms.get("a").unwrap();
+        assert_eq!(a.slot_of(1), None);
+        assert_eq!(a.member_ids(), vec![2]);
+        assert!(a.is_host(2));
+        drop(a);
+        assert_eq!(server.game_rooms.get("b").unwrap().slot_of(1), Some(0));
+        assert!(!server.channels[&chat::room_channel("a")].members.contains(&1));
+        assert!(server.channels[&chat::room_channel("b")].members.contains(&1));
+    }
+
+    /// Stands in for a websocket session, dropping whatever the server sends it.
+    struct Sink;
+
+    impl Actor for Sink {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Message> for Sink {
+        type Result = ();
+        fn handle(&mut self, _: Message, _: &mut Self::Context) {}
+    }
+
+    impl Handler<FrameMessage> for Sink {
+        type Result = ();
+        fn handle(&mut self, _: FrameMessage, _: &mut Self::Context) {}
+    }
+
+    impl Handler<Control> for Sink {
+        type Result = ();
+        fn handle(&mut self, _: Control, _: &mut Self::Context) {}
+    }
+
+    fn connect(server: &mut ChatServer, ctx: &mut Context<ChatServer>, identity: Option<Identity>, admin: bool) -> usize {
+        let sink = Sink.start();
+        let msg = Connect { addr: sink.clone().recipient(), frame_addr: sink.clone().recipient(), control_addr: sink.recipient(), identity, admin };
+        server.handle(msg, ctx)
+    }
+
+    fn text(server: &mut ChatServer, ctx: &mut Context<ChatServer>, id: usize, room: &str, text: &str) {
+        let msg = ClientMessage { id, command: "TEXT", room: room.to_string(), msg_type: ClientMessageType::TEXT(None, text.to_string()) };
+        server.handle(msg, ctx);
+    }
+
+    #[actix_web::test]
+    async fn mutes_follow_the_player_across_reconnects() {
+        let mut server = new_server();
+        let mut ctx = Context::new();
+        let identity = Identity { player_id: "p1".to_string(), name: "one".to_string() };
+        let admin = connect(&mut server, &mut ctx, None, true);
+        let player = connect(&mut server, &mut ctx, Some(identity.clone()), false);
+        let guest = connect(&mut server, &mut ctx, None, false);
+        text(&mut server, &mut ctx, admin, "", &format!("/mute {player} 60"));
+        text(&mut server, &mut ctx, admin, "", &format!("/mute {guest} 60"));
+
+        server.handle(Disconnect { id: player }, &mut ctx);
+        let player = connect(&mut server, &mut ctx, Some(identity), false);
+        assert!(server.muted_for(player).is_some());
+
+        server.handle(Disconnect { id: guest }, &mut ctx);
+        assert!(!server.mutes.contains_key(&Speaker::SESSION(guest)));
+    }
+
+    #[actix_web::test]
+    async fn typing_indicators_are_rate_limited() {
+        let mut server = new_server();
+        let mut ctx = Context::new();
+        let id = connect(&mut server, &mut ctx, None, false);
+        join(&mut server, &mut ctx, id, "a");
+
+        let relayed = (0..30).filter(|_| server.send_typing(id, "a", None).is_ok()).count();
+        assert_eq!(relayed, server.chat_config.rate_limit_messages);
+        text(&mut server, &mut ctx, id, "a", "still allowed");
+        assert_eq!(server.channels[&chat::room_channel("a")].history().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn matchmaking_leaves_the_previous_room() {
+        let mut server = new_server();
+        let mut ctx = Context::new();
+        server.control_sessions.insert(1, Sink.start().recipient());
+        join(&mut server, &mut ctx, 1, "a");
+
+        let msg = ClientMessage { id: 1, command: "MATCHMAKE", room: "a".to_string(), msg_type: ClientMessageType::MATCHMAKE(Some(1)) };
+        server.handle(msg, &mut ctx);
+        assert_eq!(server.game_rooms.get("a").unwrap().slot_of(1), None);
+        assert_eq!(server.game_rooms.get("match-1").unwrap().slot_of(1), Some(0));
+    }
+}
@@ -1,33 +1,64 @@
-use std::time::{Duration, Instant};
+use std::time::Instant;
 use actix::prelude::*;
 use actix_web_actors::ws;
-use serde::{Deserialize, Serialize};
-const HEARTBEAT: Duration = Duration::from_secs(5);
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+use serde::Deserialize;
 
+use crate::auth::Identity;
+use crate::config::SessionConfig;
+use crate::protocol::{self, ClientCommand, Encoded, FrameEncoder, FrameProtocol};
 use crate::server;
 
 #[derive(Debug)]
 pub struct WsChatSession {
-    pub id: usize,
+    /// Assigned by `ChatServer` when the session starts; `None` until then.
+    pub id: Option<usize>,
     pub hb: Instant,
     pub room: String,
     pub addr: Addr<server::ChatServer>,
+    pub frames: FrameEncoder,
+    pub snapshots: bool,
+    pub timeouts: SessionConfig,
+    pub identity: Option<Identity>,
+    /// Connected with the admin token, so may moderate chat anywhere.
+    pub admin: bool,
 }
-#[derive(PartialEq, Serialize, Deserialize, Debug)]
+#[derive(PartialEq, Deserialize, Debug)]
 pub enum ChatType {
     TYPING,
     JOIN,
     TEXT,
-    CONNECT,
-    DISCONNECT,
     MOVEMENT,
+    KEYFRAME,
+    SNAPSHOT,
+    RESUME,
+    PAUSE,
+    READY,
+    SETTINGS,
+    MATCHMAKE,
+    SUBSCRIBE,
+    UNSUBSCRIBE,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ChatMessage {
+/// The pre-versioning `{"chat_type": ..., "value": ...}` command format, still accepted from clients.
+/// Everything the server sends is a typed packet from `protocol`.
+#[derive(Deserialize, Debug)]
+pub struct ChatMessage {
     pub chat_type: ChatType,
     pub value: String,
+    #[serde(default)]
+    pub capacity: Option<usize>,
+    #[serde(default)]
+    pub cols: Option<usize>,
+    #[serde(default)]
+    pub rows: Option<usize>,
+    #[serde(default)]
+    pub level: Option<String>,
+    /// Channel a TEXT or TYPING message belongs to; clients leave it out to talk in their room.
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// Resume token for reclaiming a held player slot, sent with RESUME.
+    #[serde(default)]
+    pub token: Option<String>,
 }
 
 
@@ -42,12 +73,16 @@ impl Actor for WsChatSession {
 
         self.addr
             .send(server::Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                frame_addr: addr.clone().recipient(),
+                control_addr: addr.recipient(),
+                identity: self.identity.clone(),
+                admin: self.admin,
             })
             .into_actor(self)
             .then(|res: Result<usize, MailboxError>, act, ctx| {
                 match res {
-                    Ok(res) => act.id = res,
+                    Ok(res) => act.id = Some(res),
                     _ => ctx.stop(),
                 }
                 fut::ready(())
@@ -57,7 +92,9 @@ impl Actor for WsChatSession {
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        self.addr.do_send(server::Disconnect { id: self.id });
+        if let Some(id) = self.id {
+            self.addr.do_send(server::Disconnect { id });
+        }
         Running::Stop
     }
 }
@@ -69,6 +106,39 @@ impl Handler<server::Message> for WsChatSession {
     }
 }
 
+impl Handler<server::FrameMessage> for WsChatSession {
+    type Result = ();
+    fn handle(&mut self, msg: server::FrameMessage, ctx: &mut Self::Context) -> Self::Result {
+        match self.frames.encode(&msg.frame, msg.ack) {
+            Encoded::Text(text) => ctx.text(text),
+            Encoded::Binary(bytes) => ctx.binary(bytes),
+        }
+        if self.snapshots {
+            ctx.text(self.frames.encode_snapshot(&msg.snapshot));
+        }
+    }
+}
+
+impl Handler<server::Control> for WsChatSession {
+    type Result = ();
+    fn handle(&mut self, msg: server::Control, ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            server::Control::Kick(reason) => {
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some(reason),
+                }));
+                ctx.stop();
+            },
+            server::Control::MoveTo(room) => {
+                self.frames.request_keyframe();
+                ctx.text(protocol::encode_matched(&room));
+                self.room = room;
+            },
+        }
+    }
+}
+
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
     fn handle(&mut self, item: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         let msg = match item {
@@ -87,61 +157,14 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             ws::Message::Pong(_) => {
                 self.hb = Instant::now();
             }
-            ws::Message::Text(text) => {
-                let data_json = serde_json::from_str::<ChatMessage>(&text.to_string());
-                if let Err(err) = data_json {
-                    println!("{err}");
-                    println!("Failed to parse message: {text}");
-                    return;
-                }
-
-                let input = data_json.as_ref().unwrap();
-                match &input.chat_type {
-                    ChatType::JOIN => {
-                        self.room = input.value.clone();
-                        self.addr.do_send(server::ClientMessage {
-                            id: self.id,
-                            room: self.room.clone(),
-                            msg_type: server::ClientMessageType::JOIN,
-                        })
-                    }
-                    ChatType::MOVEMENT => {
-                        self.addr.do_send(server::ClientMessage {
-                            id: self.id,
-                            room: self.room.clone(),
-                            msg_type: server::ClientMessageType::MOVEMENT(input.value.clone()),
-                        })
-                    }
-                    ChatType::TYPING => {
-                        let chat_msg = ChatMessage {
-                            chat_type: ChatType::TYPING,
-                            value: input.value.clone(),
-                        };
-                        let msg = serde_json::to_string(&chat_msg).unwrap();
-                        self.addr.do_send(server::ClientMessage {
-                            id: self.id,
-                            room: self.room.clone(),
-                            msg_type: server::ClientMessageType::MESSAGE(msg),
-                        })
-                    }
-                    ChatType::TEXT => {
-                        let input = data_json.as_ref().unwrap();
-                        let chat_msg = ChatMessage {
-                            chat_type: ChatType::TEXT,
-                            value: input.value.clone(),
-                        };
-
-                        let msg = serde_json::to_string(&chat_msg).unwrap();
-                        self.addr.do_send(server::ClientMessage {
-                            id: self.id,
-                            room: self.room.clone(),
-                            msg_type: server::ClientMessageType::MESSAGE(msg),                            
-                        })
-                    }
-                    _ => {}
-                }
-            }
-            ws::Message::Binary(_) => println!("Unsupported binary"),
+            ws::Message::Text(text) => match protocol::decode_command(&text) {
+                Ok(command) => self.handle_command(command),
+                Err(err) => ctx.text(protocol::encode_error(err.code, None, &err.message)),
+            },
+            ws::Message::Binary(bytes) => match protocol::unpack_command(&bytes) {
+                Ok(command) => self.handle_command(command),
+                Err(err) => ctx.text(protocol::encode_error(err.code, None, &err.message)),
+            },
             ws::Message::Close(reason) => {
                 ctx.close(reason);
                 ctx.stop();
@@ -155,10 +178,54 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
 }
 
 impl WsChatSession {
+    fn handle_command(&mut self, command: ClientCommand) {
+        let Some(id) = self.id else {
+            return;
+        };
+        let name = command.name();
+        let msg_type = match command {
+            ClientCommand::JOIN { room, settings } => {
+                self.room = room;
+                server::ClientMessageType::JOIN(settings)
+            },
+            ClientCommand::RESUME { room, token } => {
+                self.room = room;
+                server::ClientMessageType::RESUME(token)
+            },
+            ClientCommand::INPUT { action, seq } => {
+                // Legacy full-grid frames have no room for the ack, so those clients get it from snapshots.
+                if seq.is_some() && self.frames.protocol == FrameProtocol::LEGACY {
+                    self.snapshots = true;
+                }
+                server::ClientMessageType::INPUT(action, seq)
+            },
+            ClientCommand::READY { ready } => server::ClientMessageType::READY(ready),
+            ClientCommand::SETTINGS { settings } => server::ClientMessageType::SETTINGS(settings),
+            ClientCommand::PAUSE { paused } => server::ClientMessageType::PAUSE(paused),
+            ClientCommand::MATCHMAKE { capacity } => server::ClientMessageType::MATCHMAKE(capacity),
+            ClientCommand::DEQUEUE {} => server::ClientMessageType::DEQUEUE,
+            ClientCommand::TEXT { text, channel } => server::ClientMessageType::TEXT(channel, text),
+            ClientCommand::TYPING { channel } => server::ClientMessageType::TYPING(channel),
+            ClientCommand::SUBSCRIBE { channel } => server::ClientMessageType::SUBSCRIBE(channel, true),
+            ClientCommand::UNSUBSCRIBE { channel } => server::ClientMessageType::SUBSCRIBE(channel, false),
+            ClientCommand::KEYFRAME {} => {
+                self.frames.request_keyframe();
+                return;
+            },
+            ClientCommand::SNAPSHOTS { enabled } => {
+                self.snapshots = enabled;
+                return;
+            },
+        };
+        self.addr.do_send(server::ClientMessage { id, command: name, room: self.room.clone(), msg_type });
+    }
+
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT, |act, ctx| {
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
-                act.addr.do_send(server::Disconnect { id: act.id });
+        ctx.run_interval(self.timeouts.heartbeat(), |act, ctx| {
+            if Instant::now().duration_since(act.hb) > act.timeouts.client_timeout() {
+                if let Some(id) = act.id {
+                    act.addr.do_send(server::Disconnect { id });
+                }
                 ctx.stop();
                 return;
             }